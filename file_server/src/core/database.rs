@@ -4,9 +4,26 @@ pub trait Database: Send + Sync + 'static {
     fn id_exists(&self, file_id: u32) -> Result<bool, Self::Error>;
 
     fn list_ids(&self) -> Result<Vec<u32>, Self::Error>;
+
+    // Returns up to limit file_ids greater than after, in order, with their
+    // aliases. If has_alias is given, only file_ids with or without aliases
+    // are returned. If namespace is given, only file_ids with an alias in it
+    // are returned.
+    fn list_page(
+        &self,
+        after: Option<u32>,
+        limit: u32,
+        has_alias: Option<bool>,
+        namespace: Option<&str>,
+    ) -> Result<Vec<(u32, Vec<String>)>, Self::Error>;
     
     // Generates a new, unique file_id.
     fn create_id(&self) -> Result<u32, Self::Error>;
+
+    // Removes file_id along with its aliases and stream hashes.
+    //
+    // Returns None if file_id does not exist.
+    fn remove_id(&self, file_id: u32) -> Result<Option<()>, Self::Error>;
     
     // Returns None if the alias does not exist.
     fn get_id(&self, alias: &str) -> Result<Option<u32>, Self::Error>;
@@ -30,43 +47,174 @@ pub trait Database: Send + Sync + 'static {
         &self,
         file_id: u32,
     ) -> Result<Option<Vec<String>>, Self::Error>;
+
+    // Returns every (alias, file_id) pair whose file_id does not exist,
+    // which the schema only prevents for databases created with it.
+    fn list_dangling_aliases(&self) -> Result<Vec<(String, u32)>, Self::Error>;
+
+    // Returns up to limit (alias, file_id) pairs, ordered by alias, whose
+    // alias is in namespace, if given, and whose name starts with prefix.
+    fn search_aliases(
+        &self,
+        namespace: Option<&str>,
+        prefix: &str,
+        limit: u32,
+    ) -> Result<Vec<(String, u32)>, Self::Error>;
+
+    // Returns None if no hashes are stored for this exact modified time and
+    // size.
+    fn get_stream_hashes(
+        &self,
+        file_id: u32,
+        modified: u64,
+        size: u64,
+    ) -> Result<Option<Vec<String>>, Self::Error>;
+
+    // Replaces any hashes previously stored for file_id.
+    fn set_stream_hashes(
+        &self,
+        file_id: u32,
+        modified: u64,
+        size: u64,
+        hashes: &[String],
+    ) -> Result<(), Self::Error>;
+
+    // Removes any hashes stored for file_id.
+    fn remove_stream_hashes(&self, file_id: u32) -> Result<(), Self::Error>;
+}
+
+// An alias such as "imdb:tt0102975" is in the "imdb" namespace. Aliases
+// without a ':' are in the "" namespace.
+pub fn alias_namespace(alias: &str) -> &str {
+    match alias.find(':') {
+        Some(i) => &alias[..i],
+        None => "",
+    }
+}
+
+// The least alias that search_aliases may return. Aliases in the ""
+// namespace have no ':' before their name.
+fn alias_search_start(namespace: Option<&str>, prefix: &str) -> String {
+    match namespace {
+        Some(namespace) if !namespace.is_empty() => {
+            format!("{}:{}", namespace, prefix)
+        },
+        _ => prefix.to_string(),
+    }
 }
 
+#[cfg(feature = "sqlite")]
 pub mod sqlite_database {
-    use std::{path::Path, sync::Mutex, convert::TryFrom};
-    use super::Database;
-    use rusqlite::{Connection, params, OptionalExtension};
+    use std::{path::{Path, PathBuf}, sync::Mutex, convert::TryFrom, ops::Deref, time::Duration};
+    use super::{Database, alias_namespace, alias_search_start};
+    use rusqlite::{Connection, OpenFlags, params, OptionalExtension};
 
+    // Idle read connections kept open beyond this are closed.
+    const MAX_IDLE_READERS: usize = 16;
+    const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+    // Writes are serialized on a single connection, while reads each take a
+    // read-only connection from a pool, so that with WAL journaling they run
+    // concurrently with each other and with the writer.
+    //
+    // The path must be a file, since every connection opens it separately.
     #[derive(Debug)]
     pub struct SqliteDatabase {
-        conn: Mutex<Connection>,
+        path: PathBuf,
+        writer: Mutex<Connection>,
+        readers: Mutex<Vec<Connection>>,
+    }
+
+    struct Reader<'a> {
+        conn: Option<Connection>,
+        pool: &'a Mutex<Vec<Connection>>,
     }
 
     impl SqliteDatabase {
         pub fn new(path: impl AsRef<Path>) -> Result<Self, rusqlite::Error> {
+            let writer = Connection::open(path.as_ref())?;
+            writer.busy_timeout(BUSY_TIMEOUT)?;
+            writer.query_row(
+                "PRAGMA journal_mode = WAL",
+                params![],
+                |row| row.get::<usize, String>(0),
+            )?;
+            writer.execute_batch(
+                "
+                PRAGMA synchronous = NORMAL;
+                PRAGMA foreign_keys = ON;
+                "
+            )?;
             let self_ = Self {
-                conn: Mutex::new(
-                    Connection::open(path)?
-                ),
+                path: path.as_ref().to_owned(),
+                writer: Mutex::new(writer),
+                readers: Mutex::new(Vec::new()),
             };
             self_.initialize()?;
             Ok(self_)
         }
+
+        fn reader(&self) -> Result<Reader, rusqlite::Error> {
+            let idle = self.readers.lock().unwrap().pop();
+            let conn = match idle {
+                Some(conn) => conn,
+                None => {
+                    let conn = Connection::open_with_flags(
+                        &self.path,
+                        OpenFlags::SQLITE_OPEN_READ_ONLY
+                            | OpenFlags::SQLITE_OPEN_NO_MUTEX
+                            | OpenFlags::SQLITE_OPEN_URI,
+                    )?;
+                    conn.busy_timeout(BUSY_TIMEOUT)?;
+                    conn
+                },
+            };
+            Ok(Reader { conn: Some(conn), pool: &self.readers })
+        }
+
+        // Runs every migration newer than the stored schema version, each in
+        // its own transaction along with the version bump.
         fn initialize(&self) -> Result<(), rusqlite::Error> {
-            self.conn.lock().unwrap().execute_batch(
+            let mut conn = self.writer.lock().unwrap();
+            conn.execute_batch(
                 "
                 BEGIN;
-                CREATE TABLE IF NOT EXISTS file_ids (
-                    id INTEGER PRIMARY KEY AUTOINCREMENT
-                );
-                CREATE TABLE IF NOT EXISTS aliases (
-                    id TEXT PRIMARY KEY,
-                    file_id INTEGER NOT NULL,
-                    FOREIGN KEY (file_id) REFERENCES file_ids (id)
+                CREATE TABLE IF NOT EXISTS schema_version (
+                    version INTEGER NOT NULL
                 );
+                INSERT INTO schema_version SELECT 0
+                    WHERE NOT EXISTS (SELECT 1 FROM schema_version);
                 COMMIT;
                 "
-            )
+            )?;
+            let version = conn.query_row(
+                "SELECT version FROM schema_version",
+                params![],
+                |row| row.get::<usize, i64>(0),
+            )? as usize;
+            if version > migrations::MIGRATIONS.len() {
+                return Err(rusqlite::Error::SqliteFailure(
+                    rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_MISMATCH),
+                    Some(format!(
+                        "database schema version {} is newer than {}",
+                        version,
+                        migrations::MIGRATIONS.len(),
+                    )),
+                ));
+            }
+            for (i, migration) in migrations::MIGRATIONS.iter()
+                .enumerate()
+                .skip(version)
+            {
+                let transaction = conn.transaction()?;
+                migration(&transaction)?;
+                transaction.execute(
+                    "UPDATE schema_version SET version = ?",
+                    params![(i + 1) as i64],
+                )?;
+                transaction.commit()?;
+            }
+            Ok(())
         }
 
         // https://github.com/rusqlite/rusqlite/blob/33d40aac107f1887803a6c9dff425c8d1fbb89e7/libsqlite3-sys/bindgen-bindings/bindgen_3.6.23.rs
@@ -84,10 +232,26 @@ pub mod sqlite_database {
         }
     }
 
+    impl Deref for Reader<'_> {
+        type Target = Connection;
+        fn deref(&self) -> &Connection {
+            self.conn.as_ref().unwrap()
+        }
+    }
+
+    impl Drop for Reader<'_> {
+        fn drop(&mut self) {
+            let mut pool = self.pool.lock().unwrap();
+            if pool.len() < MAX_IDLE_READERS {
+                pool.extend(self.conn.take());
+            }
+        }
+    }
+
     impl Database for SqliteDatabase {
         type Error = rusqlite::Error;
         fn id_exists(&self, file_id: u32) -> Result<bool, Self::Error> {
-            self.conn.lock().unwrap()
+            self.reader()?
                 .query_row(
                     "SELECT EXISTS (SELECT 1 FROM file_ids WHERE id = (?))",
                     params![file_id],
@@ -95,7 +259,7 @@ pub mod sqlite_database {
                 )
         }
         fn list_ids(&self) -> Result<Vec<u32>, Self::Error> {
-            self.conn.lock().unwrap()
+            self.reader()?
                 .prepare("SELECT id FROM file_ids")?
                 .query(params![])
                 .optional()?
@@ -103,13 +267,74 @@ pub mod sqlite_database {
                 .map(|iter| iter.collect())
                 .unwrap_or(Ok(Vec::new()))
         }
+        fn list_page(
+            &self,
+            after: Option<u32>,
+            limit: u32,
+            has_alias: Option<bool>,
+            namespace: Option<&str>,
+        ) -> Result<Vec<(u32, Vec<String>)>, Self::Error>
+        {
+            let conn = self.reader()?;
+            let mut statement = conn.prepare(
+                "
+                SELECT page.id, aliases.id FROM (
+                    SELECT id FROM file_ids
+                    WHERE id > coalesce(?1, -1)
+                    AND (?2 IS NULL OR ?2 = EXISTS (
+                        SELECT 1 FROM aliases WHERE file_id = file_ids.id
+                    ))
+                    AND (?3 IS NULL OR EXISTS (
+                        SELECT 1 FROM aliases
+                        WHERE file_id = file_ids.id AND namespace = ?3
+                    ))
+                    ORDER BY id LIMIT ?4
+                ) AS page
+                LEFT JOIN aliases ON aliases.file_id = page.id
+                ORDER BY page.id, aliases.id
+                "
+            )?;
+            let mut rows = statement.query(
+                params![after, has_alias, namespace, limit]
+            )?;
+            let mut page: Vec<(u32, Vec<String>)> = Vec::new();
+            while let Some(row) = rows.next()? {
+                let id = row.get::<usize, u32>(0)?;
+                let alias = row.get::<usize, Option<String>>(1)?;
+                match page.last_mut() {
+                    Some((last, aliases)) if *last == id => {
+                        aliases.extend(alias);
+                    },
+                    _ => page.push((id, alias.into_iter().collect())),
+                };
+            }
+            Ok(page)
+        }
         fn create_id(&self) -> Result<u32, Self::Error> {
-            let conn = self.conn.lock().unwrap();
+            let conn = self.writer.lock().unwrap();
             conn.execute("INSERT INTO file_ids DEFAULT VALUES", params![])?;
             Ok(u32::try_from(conn.last_insert_rowid()).unwrap_or(0))
         }
+        fn remove_id(&self, file_id: u32) -> Result<Option<()>, Self::Error> {
+            let mut conn = self.writer.lock().unwrap();
+            let transaction = conn.transaction()?;
+            for statement in &[
+                "DELETE FROM stream_hashes WHERE file_id = (?)",
+                "DELETE FROM stream_hash_keys WHERE file_id = (?)",
+                "DELETE FROM aliases WHERE file_id = (?)",
+            ] {
+                transaction.execute(statement, params![file_id])?;
+            }
+            match transaction.execute(
+                "DELETE FROM file_ids WHERE id = (?)",
+                params![file_id],
+            )? {
+                0 => Ok(None),
+                _ => transaction.commit().map(|_| Some(())),
+            }
+        }
         fn get_id(&self, alias: &str) -> Result<Option<u32>, Self::Error> {
-            self.conn.lock().unwrap()
+            self.reader()?
                 .query_row(
                     "SELECT file_id FROM aliases WHERE id = (?)",
                     params![alias],
@@ -122,12 +347,12 @@ pub mod sqlite_database {
             file_id: u32,
         ) -> Result<Option<()>, Self::Error>
         {
-            let mut conn = self.conn.lock().unwrap();
+            let mut conn = self.writer.lock().unwrap();
             let transaction = conn.transaction()?;
             for alias in aliases.into_iter() {
                 if let Err(e) = transaction.execute(
-                    "INSERT INTO aliases VALUES (?, ?)",
-                    params![alias, file_id],
+                    "INSERT INTO aliases (id, file_id, namespace) VALUES (?, ?, ?)",
+                    params![alias, file_id, alias_namespace(&alias)],
                 ) {
                     return Self::check_constraint(e);
                 }
@@ -140,7 +365,7 @@ pub mod sqlite_database {
             file_id: u32,
         ) -> Result<Option<()>, Self::Error>
         {
-            let mut conn = self.conn.lock().unwrap();
+            let mut conn = self.writer.lock().unwrap();
             let transaction = conn.transaction()?;
             for alias in aliases.into_iter() {
                 match transaction.execute(
@@ -159,7 +384,7 @@ pub mod sqlite_database {
             file_id: u32,
         ) -> Result<Option<Vec<String>>, Self::Error>
         {
-            self.conn.lock().unwrap()
+            self.reader()?
                 .prepare("SELECT id FROM aliases WHERE file_id = (?)")?
                 .query(params![file_id])
                 .optional()?
@@ -167,5 +392,971 @@ pub mod sqlite_database {
                 .map(|iter| iter.collect())
                 .transpose()
         }
+        fn list_dangling_aliases(
+            &self,
+        ) -> Result<Vec<(String, u32)>, Self::Error>
+        {
+            let conn = self.reader()?;
+            let mut statement = conn.prepare(
+                "
+                SELECT id, file_id FROM aliases
+                WHERE file_id NOT IN (SELECT id FROM file_ids)
+                ORDER BY id
+                "
+            )?;
+            let rows = statement
+                .query(params![])?
+                .mapped(|row| Ok((
+                    row.get::<usize, String>(0)?,
+                    row.get::<usize, u32>(1)?,
+                )))
+                .collect();
+            rows
+        }
+        fn search_aliases(
+            &self,
+            namespace: Option<&str>,
+            prefix: &str,
+            limit: u32,
+        ) -> Result<Vec<(String, u32)>, Self::Error>
+        {
+            // Aliases starting with prefix sort between prefix and prefix
+            // followed by the greatest char, so the primary key index is
+            // used instead of a scan.
+            let start = alias_search_start(namespace, prefix);
+            let end = format!("{}{}", start, std::char::MAX);
+            let conn = self.reader()?;
+            let mut statement = conn.prepare(
+                "
+                SELECT id, file_id FROM aliases
+                WHERE namespace = coalesce(?, namespace) AND id >= ? AND id < ?
+                ORDER BY id LIMIT ?
+                "
+            )?;
+            let rows = statement
+                .query(params![namespace, start, end, limit])?
+                .mapped(|row| Ok((
+                    row.get::<usize, String>(0)?,
+                    row.get::<usize, u32>(1)?,
+                )))
+                .collect();
+            rows
+        }
+        fn get_stream_hashes(
+            &self,
+            file_id: u32,
+            modified: u64,
+            size: u64,
+        ) -> Result<Option<Vec<String>>, Self::Error>
+        {
+            let conn = self.reader()?;
+            let key = conn.query_row(
+                "SELECT modified, size FROM stream_hash_keys WHERE file_id = (?)",
+                params![file_id],
+                |row| Ok((row.get::<usize, i64>(0)?, row.get::<usize, i64>(1)?)),
+            ).optional()?;
+            match key {
+                Some((m, s)) if m == modified as i64 && s == size as i64 => (),
+                _ => return Ok(None),
+            };
+            let hashes = conn
+                .prepare(
+                    "SELECT hash FROM stream_hashes WHERE file_id = (?) ORDER BY idx"
+                )?
+                .query(params![file_id])?
+                .mapped(|row| row.get::<usize, String>(0))
+                .collect::<Result<Vec<String>, Self::Error>>()?;
+            Ok(Some(hashes))
+        }
+        fn set_stream_hashes(
+            &self,
+            file_id: u32,
+            modified: u64,
+            size: u64,
+            hashes: &[String],
+        ) -> Result<(), Self::Error>
+        {
+            let mut conn = self.writer.lock().unwrap();
+            let transaction = conn.transaction()?;
+            transaction.execute(
+                "DELETE FROM stream_hashes WHERE file_id = (?)",
+                params![file_id],
+            )?;
+            transaction.execute(
+                "INSERT OR REPLACE INTO stream_hash_keys VALUES (?, ?, ?)",
+                params![file_id, modified as i64, size as i64],
+            )?;
+            for (i, hash) in hashes.iter().enumerate() {
+                transaction.execute(
+                    "INSERT INTO stream_hashes VALUES (?, ?, ?)",
+                    params![file_id, i as i64, hash],
+                )?;
+            }
+            transaction.commit()
+        }
+        fn remove_stream_hashes(&self, file_id: u32) -> Result<(), Self::Error> {
+            let mut conn = self.writer.lock().unwrap();
+            let transaction = conn.transaction()?;
+            for statement in &[
+                "DELETE FROM stream_hashes WHERE file_id = (?)",
+                "DELETE FROM stream_hash_keys WHERE file_id = (?)",
+            ] {
+                transaction.execute(statement, params![file_id])?;
+            }
+            transaction.commit()
+        }
+    }
+
+    // Append only. Databases created before schema_version existed already
+    // have some of these tables, so every migration must tolerate that.
+    mod migrations {
+        use rusqlite::{Connection, params};
+
+        pub const MIGRATIONS: &[fn(&Connection) -> rusqlite::Result<()>] = &[
+            create_ids_and_aliases,
+            create_stream_hashes,
+            add_alias_namespaces,
+            index_alias_file_ids,
+        ];
+
+        fn create_ids_and_aliases(conn: &Connection) -> rusqlite::Result<()> {
+            conn.execute_batch(
+                "
+                CREATE TABLE IF NOT EXISTS file_ids (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT
+                );
+                CREATE TABLE IF NOT EXISTS aliases (
+                    id TEXT PRIMARY KEY,
+                    file_id INTEGER NOT NULL,
+                    FOREIGN KEY (file_id) REFERENCES file_ids (id)
+                );
+                "
+            )
+        }
+
+        fn create_stream_hashes(conn: &Connection) -> rusqlite::Result<()> {
+            conn.execute_batch(
+                "
+                CREATE TABLE IF NOT EXISTS stream_hash_keys (
+                    file_id INTEGER PRIMARY KEY,
+                    modified INTEGER NOT NULL,
+                    size INTEGER NOT NULL,
+                    FOREIGN KEY (file_id) REFERENCES file_ids (id)
+                );
+                CREATE TABLE IF NOT EXISTS stream_hashes (
+                    file_id INTEGER NOT NULL,
+                    idx INTEGER NOT NULL,
+                    hash TEXT NOT NULL,
+                    PRIMARY KEY (file_id, idx),
+                    FOREIGN KEY (file_id) REFERENCES stream_hash_keys (file_id)
+                );
+                "
+            )
+        }
+
+        fn add_alias_namespaces(conn: &Connection) -> rusqlite::Result<()> {
+            let has_namespace = conn
+                .prepare("PRAGMA table_info(aliases)")?
+                .query(params![])?
+                .mapped(|row| row.get::<usize, String>(1))
+                .collect::<rusqlite::Result<Vec<String>>>()?
+                .iter()
+                .any(|column| column == "namespace");
+            if !has_namespace {
+                conn.execute_batch(
+                    "
+                    ALTER TABLE aliases
+                        ADD COLUMN namespace TEXT NOT NULL DEFAULT '';
+                    UPDATE aliases
+                        SET namespace = substr(id, 1, instr(id, ':') - 1)
+                        WHERE instr(id, ':') > 0;
+                    "
+                )?;
+            }
+            conn.execute_batch(
+                "
+                CREATE INDEX IF NOT EXISTS aliases_namespace
+                    ON aliases (namespace, id);
+                "
+            )
+        }
+
+        fn index_alias_file_ids(conn: &Connection) -> rusqlite::Result<()> {
+            conn.execute_batch(
+                "
+                CREATE INDEX IF NOT EXISTS aliases_file_id
+                    ON aliases (file_id);
+                "
+            )
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::fs;
+
+        // A database in a file of its own, removed with its WAL afterwards.
+        struct TestDatabase {
+            database: SqliteDatabase,
+            path: PathBuf,
+        }
+
+        impl TestDatabase {
+            fn new(name: &str) -> Self {
+                Self::open(test_path(name))
+            }
+
+            fn open(path: PathBuf) -> Self {
+                Self {
+                    database: SqliteDatabase::new(&path).unwrap(),
+                    path: path,
+                }
+            }
+        }
+
+        impl Deref for TestDatabase {
+            type Target = SqliteDatabase;
+            fn deref(&self) -> &SqliteDatabase {
+                &self.database
+            }
+        }
+
+        impl Drop for TestDatabase {
+            fn drop(&mut self) {
+                remove_database(&self.path);
+            }
+        }
+
+        fn test_path(name: &str) -> PathBuf {
+            let path = std::env::temp_dir().join(format!(
+                "file_server_test_{}_{}.sqlite",
+                std::process::id(),
+                name,
+            ));
+            remove_database(&path);
+            path
+        }
+
+        fn remove_database(path: &Path) {
+            for suffix in &["", "-wal", "-shm"] {
+                let mut file = path.as_os_str().to_owned();
+                file.push(suffix);
+                let _ = fs::remove_file(file);
+            }
+        }
+
+        fn strings(values: &[&str]) -> Vec<String> {
+            values.iter().map(|v| v.to_string()).collect()
+        }
+
+        // Databases created before schema_version have only the tables of the
+        // first migration.
+        #[test]
+        fn migrate_old_schema() {
+            let path = test_path("migrate_old_schema");
+            Connection::open(&path).unwrap().execute_batch(
+                "
+                CREATE TABLE file_ids (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT
+                );
+                CREATE TABLE aliases (
+                    id TEXT PRIMARY KEY,
+                    file_id INTEGER NOT NULL,
+                    FOREIGN KEY (file_id) REFERENCES file_ids (id)
+                );
+                INSERT INTO file_ids (id) VALUES (1), (2);
+                INSERT INTO aliases (id, file_id)
+                    VALUES ('imdb:tt1', 1), ('plain', 2);
+                "
+            ).unwrap();
+            let db = TestDatabase::open(path.clone());
+            let version = db.reader().unwrap()
+                .query_row(
+                    "SELECT version FROM schema_version",
+                    params![],
+                    |row| row.get::<usize, i64>(0),
+                )
+                .unwrap();
+            assert_eq!(version as usize, migrations::MIGRATIONS.len());
+            assert_eq!(db.get_id("imdb:tt1").unwrap(), Some(1));
+            assert_eq!(
+                db.search_aliases(Some("imdb"), "", 10).unwrap(),
+                vec![("imdb:tt1".to_string(), 1)],
+            );
+            assert_eq!(
+                db.search_aliases(Some(""), "", 10).unwrap(),
+                vec![("plain".to_string(), 2)],
+            );
+            db.set_stream_hashes(1, 3, 4, &strings(&["h"])).unwrap();
+            assert_eq!(
+                db.get_stream_hashes(1, 3, 4).unwrap(),
+                Some(strings(&["h"])),
+            );
+            assert_eq!(db.create_id().unwrap(), 3);
+            // Reopening finds nothing left to migrate.
+            SqliteDatabase::new(&path).unwrap();
+            assert_eq!(db.get_aliases(2).unwrap(), Some(strings(&["plain"])));
+        }
+
+        #[test]
+        fn search_aliases_by_prefix() {
+            let db = TestDatabase::new("search_aliases_by_prefix");
+            let id = db.create_id().unwrap();
+            db.create_aliases(strings(&["imdb:tt1", "imdb:tt2", "tt3"]), id)
+                .unwrap();
+            assert_eq!(
+                db.search_aliases(Some("imdb"), "tt", 10).unwrap(),
+                vec![("imdb:tt1".to_string(), id), ("imdb:tt2".to_string(), id)],
+            );
+            assert_eq!(
+                db.search_aliases(Some(""), "tt", 10).unwrap(),
+                vec![("tt3".to_string(), id)],
+            );
+            assert_eq!(
+                db.search_aliases(None, "tt", 10).unwrap(),
+                vec![("tt3".to_string(), id)],
+            );
+            assert_eq!(db.search_aliases(None, "", 1).unwrap().len(), 1);
+        }
+
+        #[test]
+        fn list_page_filters() {
+            let db = TestDatabase::new("list_page_filters");
+            let ids: Vec<u32> = (0..4).map(|_| db.create_id().unwrap()).collect();
+            db.create_aliases(strings(&["imdb:tt1", "b"]), ids[1]).unwrap();
+            db.create_aliases(strings(&["c"]), ids[2]).unwrap();
+            let page = db.list_page(None, 2, None, None).unwrap();
+            assert_eq!(page, vec![
+                (ids[0], vec![]),
+                (ids[1], strings(&["b", "imdb:tt1"])),
+            ]);
+            let page = db.list_page(Some(ids[1]), 10, Some(false), None)
+                .unwrap();
+            assert_eq!(page, vec![(ids[3], vec![])]);
+            let page = db.list_page(None, 10, None, Some("imdb")).unwrap();
+            assert_eq!(page, vec![(ids[1], strings(&["b", "imdb:tt1"]))]);
+        }
+    }
+}
+
+#[cfg(feature = "postgres")]
+pub mod postgres_database {
+    use std::{fmt, sync::Mutex, convert::TryFrom, ops::{Deref, DerefMut}};
+    use super::{Database, alias_namespace, alias_search_start};
+    use postgres::{Client, Config, NoTls};
+
+    // Idle connections kept open beyond this are closed.
+    const MAX_IDLE_CLIENTS: usize = 16;
+
+    // Each call takes a connection from a pool, opening a new one if none
+    // are idle. Postgres serializes conflicting writes itself.
+    //
+    // Ids are stored as BIGINT, since u32 does not fit in INTEGER.
+    pub struct PgDatabase {
+        config: Config,
+        clients: Mutex<Vec<Client>>,
+    }
+
+    struct PooledClient<'a> {
+        client: Option<Client>,
+        pool: &'a Mutex<Vec<Client>>,
+    }
+
+    type InitError = Box<dyn std::error::Error + Send + Sync>;
+
+    impl PgDatabase {
+        // params is a libpq style connection string, either
+        // "host=localhost user=postgres" or "postgresql://...".
+        pub fn new(params: &str) -> Result<Self, InitError> {
+            Self::with_config(params.parse()?)
+        }
+
+        pub fn with_config(config: Config) -> Result<Self, InitError> {
+            let self_ = Self {
+                config: config,
+                clients: Mutex::new(Vec::new()),
+            };
+            self_.initialize()?;
+            Ok(self_)
+        }
+
+        fn client(&self) -> Result<PooledClient, postgres::Error> {
+            let idle = self.clients.lock().unwrap().pop();
+            let client = match idle {
+                Some(client) => client,
+                None => self.config.connect(NoTls)?,
+            };
+            Ok(PooledClient { client: Some(client), pool: &self.clients })
+        }
+
+        // Runs every migration newer than the stored schema version in one
+        // transaction, holding a lock on schema_version so that servers
+        // starting together do not both run them.
+        fn initialize(&self) -> Result<(), InitError> {
+            let mut client = self.client()?;
+            client.batch_execute(
+                "
+                CREATE TABLE IF NOT EXISTS schema_version (
+                    version INTEGER NOT NULL
+                );
+                "
+            )?;
+            let mut transaction = client.transaction()?;
+            transaction.batch_execute(
+                "
+                LOCK TABLE schema_version IN EXCLUSIVE MODE;
+                INSERT INTO schema_version SELECT 0
+                    WHERE NOT EXISTS (SELECT 1 FROM schema_version);
+                "
+            )?;
+            let version = transaction
+                .query_one("SELECT version FROM schema_version", &[])?
+                .get::<usize, i32>(0) as usize;
+            if version > MIGRATIONS.len() {
+                return Err(format!(
+                    "database schema version {} is newer than {}",
+                    version,
+                    MIGRATIONS.len(),
+                ).into());
+            }
+            for migration in &MIGRATIONS[version..] {
+                transaction.batch_execute(migration)?;
+            }
+            transaction.execute(
+                "UPDATE schema_version SET version = $1",
+                &[&(MIGRATIONS.len() as i32)],
+            )?;
+            transaction.commit()?;
+            Ok(())
+        }
+
+        // Class 23 is integrity constraint violation, which covers both
+        // unique and foreign key violations.
+        // https://www.postgresql.org/docs/current/errcodes-appendix.html
+        fn check_constraint<T>(
+            e: postgres::Error,
+        ) -> Result<Option<T>, postgres::Error>
+        {
+            match e.code() {
+                Some(code) if code.code().starts_with("23") => Ok(None),
+                _ => Err(e),
+            }
+        }
+    }
+
+    // Client does not implement Debug.
+    impl fmt::Debug for PgDatabase {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.debug_struct("PgDatabase")
+                .field("config", &self.config)
+                .field("idle_clients", &self.clients.lock().unwrap().len())
+                .finish()
+        }
+    }
+
+    impl Deref for PooledClient<'_> {
+        type Target = Client;
+        fn deref(&self) -> &Client {
+            self.client.as_ref().unwrap()
+        }
+    }
+
+    impl DerefMut for PooledClient<'_> {
+        fn deref_mut(&mut self) -> &mut Client {
+            self.client.as_mut().unwrap()
+        }
+    }
+
+    impl Drop for PooledClient<'_> {
+        fn drop(&mut self) {
+            let mut pool = self.pool.lock().unwrap();
+            if pool.len() < MAX_IDLE_CLIENTS {
+                pool.extend(self.client.take().filter(|c| !c.is_closed()));
+            }
+        }
     }
-}
\ No newline at end of file
+
+    impl Database for PgDatabase {
+        type Error = postgres::Error;
+        fn id_exists(&self, file_id: u32) -> Result<bool, Self::Error> {
+            self.client()?
+                .query_one(
+                    "SELECT EXISTS (SELECT 1 FROM file_ids WHERE id = $1)",
+                    &[&i64::from(file_id)],
+                )
+                .map(|row| row.get::<usize, bool>(0))
+        }
+        fn list_ids(&self) -> Result<Vec<u32>, Self::Error> {
+            Ok(self.client()?
+                .query("SELECT id FROM file_ids", &[])?
+                .iter()
+                .map(|row| row.get::<usize, i64>(0) as u32)
+                .collect()
+            )
+        }
+        fn list_page(
+            &self,
+            after: Option<u32>,
+            limit: u32,
+            has_alias: Option<bool>,
+            namespace: Option<&str>,
+        ) -> Result<Vec<(u32, Vec<String>)>, Self::Error>
+        {
+            let rows = self.client()?.query(
+                "
+                SELECT page.id, aliases.id FROM (
+                    SELECT id FROM file_ids
+                    WHERE id > coalesce($1::BIGINT, -1)
+                    AND ($2::BOOLEAN IS NULL OR $2 = EXISTS (
+                        SELECT 1 FROM aliases WHERE file_id = file_ids.id
+                    ))
+                    AND ($3::TEXT IS NULL OR EXISTS (
+                        SELECT 1 FROM aliases
+                        WHERE file_id = file_ids.id AND namespace = $3
+                    ))
+                    ORDER BY id LIMIT $4
+                ) AS page
+                LEFT JOIN aliases ON aliases.file_id = page.id
+                ORDER BY page.id, aliases.id
+                ",
+                &[
+                    &after.map(i64::from),
+                    &has_alias,
+                    &namespace,
+                    &i64::from(limit),
+                ],
+            )?;
+            let mut page: Vec<(u32, Vec<String>)> = Vec::new();
+            for row in rows.iter() {
+                let id = row.get::<usize, i64>(0) as u32;
+                let alias = row.get::<usize, Option<String>>(1);
+                match page.last_mut() {
+                    Some((last, aliases)) if *last == id => {
+                        aliases.extend(alias);
+                    },
+                    _ => page.push((id, alias.into_iter().collect())),
+                };
+            }
+            Ok(page)
+        }
+        fn create_id(&self) -> Result<u32, Self::Error> {
+            let row = self.client()?.query_one(
+                "INSERT INTO file_ids DEFAULT VALUES RETURNING id",
+                &[],
+            )?;
+            Ok(u32::try_from(row.get::<usize, i64>(0)).unwrap_or(0))
+        }
+        fn remove_id(&self, file_id: u32) -> Result<Option<()>, Self::Error> {
+            let file_id = i64::from(file_id);
+            let mut client = self.client()?;
+            let mut transaction = client.transaction()?;
+            for statement in &[
+                "DELETE FROM stream_hashes WHERE file_id = $1",
+                "DELETE FROM stream_hash_keys WHERE file_id = $1",
+                "DELETE FROM aliases WHERE file_id = $1",
+            ] {
+                transaction.execute(*statement, &[&file_id])?;
+            }
+            match transaction.execute(
+                "DELETE FROM file_ids WHERE id = $1",
+                &[&file_id],
+            )? {
+                0 => Ok(None),
+                _ => transaction.commit().map(|_| Some(())),
+            }
+        }
+        fn get_id(&self, alias: &str) -> Result<Option<u32>, Self::Error> {
+            Ok(self.client()?
+                .query_opt(
+                    "SELECT file_id FROM aliases WHERE id = $1",
+                    &[&alias],
+                )?
+                .map(|row| row.get::<usize, i64>(0) as u32)
+            )
+        }
+        fn create_aliases(
+            &self,
+            aliases: Vec<String>,
+            file_id: u32,
+        ) -> Result<Option<()>, Self::Error>
+        {
+            let mut client = self.client()?;
+            let mut transaction = client.transaction()?;
+            for alias in aliases.into_iter() {
+                if let Err(e) = transaction.execute(
+                    "INSERT INTO aliases (id, file_id, namespace) VALUES ($1, $2, $3)",
+                    &[&alias, &i64::from(file_id), &alias_namespace(&alias)],
+                ) {
+                    return Self::check_constraint(e);
+                }
+            }
+            transaction.commit().map(|_| Some(()))
+        }
+        fn remove_aliases(
+            &self,
+            aliases: Vec<String>,
+            file_id: u32,
+        ) -> Result<Option<()>, Self::Error>
+        {
+            let mut client = self.client()?;
+            let mut transaction = client.transaction()?;
+            for alias in aliases.into_iter() {
+                match transaction.execute(
+                    "DELETE FROM aliases WHERE id = $1 AND file_id = $2",
+                    &[&alias, &i64::from(file_id)],
+                ) {
+                    Ok(0) => return Ok(None),
+                    Ok(_) => (),
+                    Err(e) => return Self::check_constraint(e),
+                };
+            }
+            transaction.commit().map(|_| Some(()))
+        }
+        fn get_aliases(
+            &self,
+            file_id: u32,
+        ) -> Result<Option<Vec<String>>, Self::Error>
+        {
+            let aliases: Vec<String> = self.client()?
+                .query(
+                    "SELECT id FROM aliases WHERE file_id = $1",
+                    &[&i64::from(file_id)],
+                )?
+                .iter()
+                .map(|row| row.get::<usize, String>(0))
+                .collect();
+            match aliases.is_empty() {
+                true => Ok(None),
+                false => Ok(Some(aliases)),
+            }
+        }
+        fn list_dangling_aliases(
+            &self,
+        ) -> Result<Vec<(String, u32)>, Self::Error>
+        {
+            Ok(self.client()?
+                .query(
+                    "
+                    SELECT id, file_id FROM aliases
+                    WHERE file_id NOT IN (SELECT id FROM file_ids)
+                    ORDER BY id
+                    ",
+                    &[],
+                )?
+                .iter()
+                .map(|row| (
+                    row.get::<usize, String>(0),
+                    row.get::<usize, i64>(1) as u32,
+                ))
+                .collect()
+            )
+        }
+        fn search_aliases(
+            &self,
+            namespace: Option<&str>,
+            prefix: &str,
+            limit: u32,
+        ) -> Result<Vec<(String, u32)>, Self::Error>
+        {
+            // As with SQLite, a range over the primary key rather than LIKE,
+            // which the "C" collation of aliases.id makes bytewise.
+            let start = alias_search_start(namespace, prefix);
+            let end = format!("{}{}", start, std::char::MAX);
+            Ok(self.client()?
+                .query(
+                    "
+                    SELECT id, file_id FROM aliases
+                    WHERE namespace = coalesce($1, namespace)
+                    AND id >= $2 AND id < $3
+                    ORDER BY id LIMIT $4
+                    ",
+                    &[&namespace, &start, &end, &i64::from(limit)],
+                )?
+                .iter()
+                .map(|row| (
+                    row.get::<usize, String>(0),
+                    row.get::<usize, i64>(1) as u32,
+                ))
+                .collect()
+            )
+        }
+        fn get_stream_hashes(
+            &self,
+            file_id: u32,
+            modified: u64,
+            size: u64,
+        ) -> Result<Option<Vec<String>>, Self::Error>
+        {
+            let file_id = i64::from(file_id);
+            let mut client = self.client()?;
+            let key = client.query_opt(
+                "SELECT modified, size FROM stream_hash_keys WHERE file_id = $1",
+                &[&file_id],
+            )?.map(|row| (row.get::<usize, i64>(0), row.get::<usize, i64>(1)));
+            match key {
+                Some((m, s)) if m == modified as i64 && s == size as i64 => (),
+                _ => return Ok(None),
+            };
+            let hashes = client
+                .query(
+                    "SELECT hash FROM stream_hashes WHERE file_id = $1 ORDER BY idx",
+                    &[&file_id],
+                )?
+                .iter()
+                .map(|row| row.get::<usize, String>(0))
+                .collect();
+            Ok(Some(hashes))
+        }
+        fn set_stream_hashes(
+            &self,
+            file_id: u32,
+            modified: u64,
+            size: u64,
+            hashes: &[String],
+        ) -> Result<(), Self::Error>
+        {
+            let file_id = i64::from(file_id);
+            let mut client = self.client()?;
+            let mut transaction = client.transaction()?;
+            transaction.execute(
+                "DELETE FROM stream_hashes WHERE file_id = $1",
+                &[&file_id],
+            )?;
+            transaction.execute(
+                "
+                INSERT INTO stream_hash_keys VALUES ($1, $2, $3)
+                ON CONFLICT (file_id)
+                DO UPDATE SET modified = $2, size = $3
+                ",
+                &[&file_id, &(modified as i64), &(size as i64)],
+            )?;
+            for (i, hash) in hashes.iter().enumerate() {
+                transaction.execute(
+                    "INSERT INTO stream_hashes VALUES ($1, $2, $3)",
+                    &[&file_id, &(i as i32), hash],
+                )?;
+            }
+            transaction.commit()
+        }
+        fn remove_stream_hashes(&self, file_id: u32) -> Result<(), Self::Error> {
+            let file_id = i64::from(file_id);
+            let mut client = self.client()?;
+            let mut transaction = client.transaction()?;
+            for statement in &[
+                "DELETE FROM stream_hashes WHERE file_id = $1",
+                "DELETE FROM stream_hash_keys WHERE file_id = $1",
+            ] {
+                transaction.execute(*statement, &[&file_id])?;
+            }
+            transaction.commit()
+        }
+    }
+
+    // Append only.
+    const MIGRATIONS: &[&str] = &[
+        "
+        CREATE TABLE file_ids (
+            id BIGSERIAL PRIMARY KEY
+        );
+        CREATE TABLE aliases (
+            id TEXT COLLATE \"C\" PRIMARY KEY,
+            file_id BIGINT NOT NULL REFERENCES file_ids (id),
+            namespace TEXT COLLATE \"C\" NOT NULL DEFAULT ''
+        );
+        CREATE INDEX aliases_namespace ON aliases (namespace, id);
+        CREATE INDEX aliases_file_id ON aliases (file_id);
+        CREATE TABLE stream_hash_keys (
+            file_id BIGINT PRIMARY KEY REFERENCES file_ids (id),
+            modified BIGINT NOT NULL,
+            size BIGINT NOT NULL
+        );
+        CREATE TABLE stream_hashes (
+            file_id BIGINT NOT NULL REFERENCES stream_hash_keys (file_id),
+            idx INTEGER NOT NULL,
+            hash TEXT NOT NULL,
+            PRIMARY KEY (file_id, idx)
+        );
+        ",
+    ];
+
+    // These run against the Postgres in FILE_SERVER_TEST_POSTGRES, or a
+    // local one, each in a schema of its own that is dropped afterwards:
+    //
+    //     cargo test --no-default-features --features postgres -- --ignored
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static SCHEMAS: AtomicUsize = AtomicUsize::new(0);
+
+        struct TestDatabase {
+            database: PgDatabase,
+            schema: String,
+        }
+
+        impl TestDatabase {
+            fn new() -> Self {
+                let params = std::env::var("FILE_SERVER_TEST_POSTGRES")
+                    .unwrap_or("host=localhost user=postgres".to_string());
+                let mut config: Config = params.parse().unwrap();
+                let schema = format!(
+                    "file_server_test_{}_{}",
+                    std::process::id(),
+                    SCHEMAS.fetch_add(1, Ordering::SeqCst),
+                );
+                config.connect(NoTls).unwrap()
+                    .batch_execute(&format!("CREATE SCHEMA {}", schema))
+                    .unwrap();
+                config.options(&format!("-c search_path={}", schema));
+                Self {
+                    database: PgDatabase::with_config(config).unwrap(),
+                    schema: schema,
+                }
+            }
+        }
+
+        impl Deref for TestDatabase {
+            type Target = PgDatabase;
+            fn deref(&self) -> &PgDatabase {
+                &self.database
+            }
+        }
+
+        impl Drop for TestDatabase {
+            fn drop(&mut self) {
+                let _ = self.database.client().and_then(|mut c| c.batch_execute(
+                    &format!("DROP SCHEMA {} CASCADE", self.schema)
+                ));
+            }
+        }
+
+        fn strings(values: &[&str]) -> Vec<String> {
+            values.iter().map(|v| v.to_string()).collect()
+        }
+
+        #[test]
+        #[ignore]
+        fn initialize_is_idempotent() {
+            let db = TestDatabase::new();
+            db.initialize().unwrap();
+            let version = db.client().unwrap()
+                .query_one("SELECT version FROM schema_version", &[])
+                .unwrap()
+                .get::<usize, i32>(0);
+            assert_eq!(version as usize, MIGRATIONS.len());
+        }
+
+        #[test]
+        #[ignore]
+        fn aliases_follow_constraints() {
+            let db = TestDatabase::new();
+            let a = db.create_id().unwrap();
+            let b = db.create_id().unwrap();
+            assert!(db.id_exists(a).unwrap());
+            assert_eq!(
+                db.create_aliases(strings(&["imdb:tt1", "x"]), a).unwrap(),
+                Some(()),
+            );
+            // Duplicates and unknown ids violate constraints, and roll back
+            // the aliases created alongside them.
+            assert_eq!(
+                db.create_aliases(strings(&["y", "x"]), b).unwrap(),
+                None,
+            );
+            assert_eq!(db.get_id("y").unwrap(), None);
+            assert_eq!(
+                db.create_aliases(strings(&["z"]), b + 100).unwrap(),
+                None,
+            );
+            assert_eq!(db.get_id("imdb:tt1").unwrap(), Some(a));
+            assert_eq!(db.get_aliases(b).unwrap(), None);
+            assert_eq!(
+                db.remove_aliases(strings(&["x"]), b).unwrap(),
+                None,
+            );
+            assert_eq!(
+                db.remove_aliases(strings(&["x"]), a).unwrap(),
+                Some(()),
+            );
+            assert_eq!(
+                db.get_aliases(a).unwrap(),
+                Some(strings(&["imdb:tt1"])),
+            );
+            assert!(db.list_dangling_aliases().unwrap().is_empty());
+        }
+
+        #[test]
+        #[ignore]
+        fn remove_id_removes_everything() {
+            let db = TestDatabase::new();
+            let id = db.create_id().unwrap();
+            db.create_aliases(strings(&["a"]), id).unwrap();
+            db.set_stream_hashes(id, 1, 2, &strings(&["h"])).unwrap();
+            assert_eq!(db.remove_id(id).unwrap(), Some(()));
+            assert_eq!(db.remove_id(id).unwrap(), None);
+            assert!(!db.id_exists(id).unwrap());
+            assert_eq!(db.get_id("a").unwrap(), None);
+            assert_eq!(db.get_stream_hashes(id, 1, 2).unwrap(), None);
+        }
+
+        #[test]
+        #[ignore]
+        fn list_page_filters() {
+            let db = TestDatabase::new();
+            let ids: Vec<u32> = (0..4).map(|_| db.create_id().unwrap()).collect();
+            db.create_aliases(strings(&["imdb:tt1", "b"]), ids[1]).unwrap();
+            db.create_aliases(strings(&["c"]), ids[2]).unwrap();
+            let page = db.list_page(None, 2, None, None).unwrap();
+            assert_eq!(page, vec![
+                (ids[0], vec![]),
+                (ids[1], strings(&["b", "imdb:tt1"])),
+            ]);
+            let page = db.list_page(Some(ids[1]), 10, Some(false), None)
+                .unwrap();
+            assert_eq!(page, vec![(ids[3], vec![])]);
+            let page = db.list_page(None, 10, None, Some("imdb")).unwrap();
+            assert_eq!(page, vec![(ids[1], strings(&["b", "imdb:tt1"]))]);
+        }
+
+        #[test]
+        #[ignore]
+        fn search_aliases_by_prefix() {
+            let db = TestDatabase::new();
+            let id = db.create_id().unwrap();
+            db.create_aliases(strings(&["imdb:tt1", "imdb:tt2", "tt3"]), id)
+                .unwrap();
+            assert_eq!(
+                db.search_aliases(Some("imdb"), "tt", 10).unwrap(),
+                vec![("imdb:tt1".to_string(), id), ("imdb:tt2".to_string(), id)],
+            );
+            assert_eq!(
+                db.search_aliases(Some(""), "tt", 10).unwrap(),
+                vec![("tt3".to_string(), id)],
+            );
+            assert_eq!(
+                db.search_aliases(None, "tt", 10).unwrap(),
+                vec![("tt3".to_string(), id)],
+            );
+            assert_eq!(db.search_aliases(None, "", 1).unwrap().len(), 1);
+        }
+
+        #[test]
+        #[ignore]
+        fn stream_hashes_are_keyed() {
+            let db = TestDatabase::new();
+            let id = db.create_id().unwrap();
+            assert_eq!(db.get_stream_hashes(id, 1, 2).unwrap(), None);
+            db.set_stream_hashes(id, 1, 2, &strings(&["a", "b"])).unwrap();
+            db.set_stream_hashes(id, 3, 4, &strings(&["c"])).unwrap();
+            assert_eq!(db.get_stream_hashes(id, 1, 2).unwrap(), None);
+            assert_eq!(
+                db.get_stream_hashes(id, 3, 4).unwrap(),
+                Some(strings(&["c"])),
+            );
+            db.remove_stream_hashes(id).unwrap();
+            assert_eq!(db.get_stream_hashes(id, 3, 4).unwrap(), None);
+        }
+    }
+}
@@ -7,14 +7,23 @@ pub enum Error {
     DatabaseError(Box<dyn StdError>),
     FileSystemError(std::io::Error),
     SystemTimeError(std::time::SystemTimeError),
+    ProcessSpawnError(std::io::Error),
+    ProcessExitError(std::process::ExitStatus),
+    InvalidProcessOutput(serde_json::Error),
+    InvalidHashOutput(String),
     Infallible(Option<&'static str>),
     StreamHashesNotFound,
+    StreamNotFound(String),
+    StreamNotExtractable(String),
+    InvalidChapters,
     FileNotFound,
     IdNotFound(u32),
     AliasNotFound(String),
     AliasesAlreadyExist,
     AliasDoesNotMatchId(String, u32),
     InvalidMediaFile,
+    UploadTooLarge(u64),
+    InsufficientStorage,
 }
 
 impl Error {
@@ -28,7 +37,63 @@ impl Error {
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
-        Ok(())
+        match self {
+            Self::FileMapError(e) => write!(f, "file map error: {}", e),
+            Self::CacheError(e) => write!(f, "cache error: {}", e),
+            Self::DatabaseError(e) => write!(f, "database error: {}", e),
+            Self::FileSystemError(e) => write!(f, "file system error: {}", e),
+            Self::SystemTimeError(e) => write!(f, "system time error: {}", e),
+            Self::ProcessSpawnError(e) => write!(
+                f, "failed to run media process: {}", e
+            ),
+            Self::ProcessExitError(s) => write!(
+                f, "media process exited unsuccessfully: {}", s
+            ),
+            Self::InvalidProcessOutput(e) => write!(
+                f, "media process returned invalid JSON: {}", e
+            ),
+            Self::InvalidHashOutput(line) => write!(
+                f, "media process returned an invalid stream hash: {:?}", line
+            ),
+            Self::Infallible(Some(s)) => write!(
+                f, "an infallible operation failed: {}", s
+            ),
+            Self::Infallible(None) => write!(
+                f, "an infallible operation failed"
+            ),
+            Self::StreamHashesNotFound => write!(
+                f, "not all stream hashes were found in the file"
+            ),
+            Self::StreamNotFound(hash) => write!(
+                f, "stream {} not found in the file", hash
+            ),
+            Self::StreamNotExtractable(hash) => write!(
+                f, "stream {} cannot be stored in a standalone file", hash
+            ),
+            Self::InvalidChapters => write!(
+                f, "chapters must have a start no later than their end"
+            ),
+            Self::FileNotFound => write!(f, "file not found"),
+            Self::IdNotFound(id) => write!(f, "id {} not found", id),
+            Self::AliasNotFound(alias) => write!(
+                f, "alias {:?} not found", alias
+            ),
+            Self::AliasesAlreadyExist => write!(
+                f, "at least one alias already exists"
+            ),
+            Self::AliasDoesNotMatchId(alias, id) => write!(
+                f, "alias {:?} belongs to id {}", alias, id
+            ),
+            Self::InvalidMediaFile => write!(
+                f, "the file has no audio, video or subtitle streams"
+            ),
+            Self::UploadTooLarge(limit) => write!(
+                f, "the upload is larger than {} bytes", limit
+            ),
+            Self::InsufficientStorage => write!(
+                f, "there is not enough free space for the upload"
+            ),
+        }
     }
 }
 
@@ -40,6 +105,8 @@ impl StdError for Error {
             Self::DatabaseError(e) => Some(&**e),
             Self::FileSystemError(e) => Some(e),
             Self::SystemTimeError(e) => Some(e),
+            Self::ProcessSpawnError(e) => Some(e),
+            Self::InvalidProcessOutput(e) => Some(e),
             _ => None,
         }
     }
@@ -47,6 +114,15 @@ impl StdError for Error {
 
 impl From<super::media_mixer::Error> for Error {
     fn from(value: super::media_mixer::Error) -> Self {
-        unimplemented!()
+        use super::media_mixer::Error as MixerError;
+        match value {
+            MixerError::ProcessError(e) => Self::ProcessSpawnError(e),
+            MixerError::ExitError(s) => Self::ProcessExitError(s),
+            MixerError::FileSystemError(e) => Self::FileSystemError(e),
+            MixerError::InvalidOutput(e) => Self::InvalidProcessOutput(e),
+            MixerError::InvalidHashOutput(s) => Self::InvalidHashOutput(s),
+            MixerError::UploadTooLarge(limit) => Self::UploadTooLarge(limit),
+            MixerError::InsufficientSpace => Self::InsufficientStorage,
+        }
     }
 }
\ No newline at end of file
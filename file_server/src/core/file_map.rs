@@ -1,24 +1,156 @@
 pub trait FileMap: Send + Sync + 'static {
     type Error: std::error::Error + 'static;
+    // Returns the path of the file stored for key, or the path a new file
+    // should be written to if there is none. A new file may be written with
+    // a different extension, which later calls must then return.
     fn get(&self, key: &u32) -> Result<std::path::PathBuf, Self::Error>;
+    // Called with the file locked for writing, after a new file has been
+    // written to path for key, replacing any previous one.
+    fn store(
+        &self,
+        _key: &u32,
+        _path: &std::path::Path,
+    ) -> Result<(), Self::Error>
+    {
+        Ok(())
+    }
+    // Called after the file for key has been removed.
+    fn release(&self, _key: &u32) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
 
 pub mod local_file_map {
     use super::FileMap;
-    use std::{io, path::{Path, PathBuf}};
+    use crate::core::media_mixer::CONTAINER_EXTENSIONS;
+    use std::{io, fs, path::{Path, PathBuf}};
 
     #[derive(Debug)]
     pub struct LocalFileMap {
         base_path: PathBuf,
+        // Used for new files whose container is not otherwise chosen.
         extension: &'static str,
+        layout: Layout,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Layout {
+        // base_path/<id>.<ext>
+        Flat,
+        // base_path/<id & 0xff>/<(id >> 8) & 0xff>/<id>.<ext>, as two hex
+        // digits each, so that sequential ids are spread across directories.
+        Sharded,
     }
 
     impl LocalFileMap {
         pub fn new(path: impl AsRef<Path>, extension: &'static str) -> Self {
+            Self::with_layout(path, extension, Layout::Flat)
+        }
+
+        pub fn with_layout(
+            path: impl AsRef<Path>,
+            extension: &'static str,
+            layout: Layout,
+        ) -> Self
+        {
             Self {
                 base_path: path.as_ref().to_owned(),
                 extension: extension,
+                layout: layout,
+            }
+        }
+
+        // Moves every file stored in the from layout into this map's layout.
+        // Returns the number of files moved.
+        pub fn migrate(&self, from: Layout) -> io::Result<usize> {
+            if from == self.layout {
+                return Ok(0);
             }
+            let mut moved = 0;
+            for (key, path) in self.entries(from)? {
+                let mut target = self.path_in(self.layout, key);
+                if let Some(ext) = path.extension() {
+                    target.set_extension(ext);
+                }
+                if target.exists() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!(
+                            "cannot move {} to {}: target exists",
+                            path.display(),
+                            target.display(),
+                        ),
+                    ));
+                }
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::rename(&path, &target)?;
+                moved += 1;
+            }
+            Ok(moved)
+        }
+
+        pub fn base_path(&self) -> &Path {
+            &self.base_path
+        }
+
+        // Every file stored in this map's layout, by id.
+        pub fn files(&self) -> io::Result<Vec<(u32, PathBuf)>> {
+            self.entries(self.layout)
+        }
+
+        // Every file stored in the given layout whose stem is an id. Hidden
+        // files, such as those being written by media_mixer, are skipped.
+        fn entries(&self, layout: Layout) -> io::Result<Vec<(u32, PathBuf)>> {
+            let mut dirs = vec![self.base_path.clone()];
+            if layout == Layout::Sharded {
+                for _ in 0..2 {
+                    dirs = dirs.into_iter()
+                        .map(|dir| Self::children(&dir, true))
+                        .collect::<io::Result<Vec<_>>>()?
+                        .into_iter()
+                        .flatten()
+                        .collect();
+                }
+            }
+            let mut entries = Vec::new();
+            for dir in dirs {
+                for path in Self::children(&dir, false)? {
+                    let key = path.file_stem()
+                        .and_then(|s| s.to_str())
+                        .and_then(|s| s.parse::<u32>().ok());
+                    if let Some(key) = key {
+                        entries.push((key, path));
+                    }
+                }
+            }
+            Ok(entries)
+        }
+
+        pub(super) fn children(dir: &Path, dirs: bool) -> io::Result<Vec<PathBuf>> {
+            let mut children = Vec::new();
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let hidden = entry.file_name()
+                    .to_str()
+                    .map_or(true, |s| s.starts_with('.'));
+                if !hidden && entry.file_type()?.is_dir() == dirs {
+                    children.push(entry.path());
+                }
+            }
+            Ok(children)
+        }
+
+        fn path_in(&self, layout: Layout, key: u32) -> PathBuf {
+            let mut path = self.base_path.clone();
+            if layout == Layout::Sharded {
+                path.push(format!("{:02x}", key & 0xff));
+                path.push(format!("{:02x}", (key >> 8) & 0xff));
+            }
+            path.push(key.to_string());
+            path.set_extension(self.extension);
+            path
         }
     }
 
@@ -26,10 +158,340 @@ pub mod local_file_map {
         type Error = io::Error;
         fn get(&self, key: &u32) -> Result<PathBuf, Self::Error> {
             self.base_path.metadata()?;
-            let mut path = self.base_path.clone();
-            path.push(key.to_string());
-            path.set_extension(self.extension);
-            Ok(path)
+            let path = self.path_in(self.layout, *key);
+            if path.is_file() {
+                return Ok(path);
+            }
+            Ok(CONTAINER_EXTENSIONS.iter()
+                .map(|ext| path.with_extension(ext))
+                .find(|p| p.is_file())
+                .unwrap_or(path)
+            )
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::core::test_dir::TestDir;
+
+        #[test]
+        fn migrate_to_sharded() {
+            let dir = TestDir::new("migrate_to_sharded");
+            let base = dir.path();
+            for name in &["1.mkv", "258.mp4", ".3.input", "notes.txt"] {
+                fs::write(base.join(name), name).unwrap();
+            }
+            let map = LocalFileMap::with_layout(base, "mkv", Layout::Sharded);
+            assert_eq!(map.migrate(Layout::Flat).unwrap(), 2);
+            assert_eq!(map.get(&1).unwrap(), base.join("01/00/1.mkv"));
+            assert_eq!(map.get(&258).unwrap(), base.join("02/01/258.mp4"));
+            assert_eq!(
+                fs::read(base.join("02/01/258.mp4")).unwrap(),
+                b"258.mp4",
+            );
+            assert!(base.join(".3.input").is_file());
+            assert!(base.join("notes.txt").is_file());
+            let mut files = map.files().unwrap();
+            files.sort();
+            assert_eq!(files, vec![
+                (1, base.join("01/00/1.mkv")),
+                (258, base.join("02/01/258.mp4")),
+            ]);
+            assert_eq!(map.migrate(Layout::Flat).unwrap(), 0);
+            assert_eq!(map.migrate(Layout::Sharded).unwrap(), 0);
+            // A file in both layouts is left where it is.
+            fs::write(base.join("1.mkv"), "1.mkv").unwrap();
+            let e = map.migrate(Layout::Flat).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+            assert!(base.join("1.mkv").is_file());
+        }
+
+        #[test]
+        fn migrate_to_flat() {
+            let dir = TestDir::new("migrate_to_flat");
+            let base = dir.path();
+            fs::create_dir_all(base.join("02/01")).unwrap();
+            fs::write(base.join("02/01/258.mp4"), "258.mp4").unwrap();
+            let map = LocalFileMap::new(base, "mkv");
+            assert_eq!(map.migrate(Layout::Sharded).unwrap(), 1);
+            assert_eq!(map.get(&258).unwrap(), base.join("258.mp4"));
+            assert_eq!(
+                map.files().unwrap(),
+                vec![(258, base.join("258.mp4"))],
+            );
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(feature = "dedup")]
+pub mod content_file_map {
+    use super::{FileMap, local_file_map::{LocalFileMap, Layout}};
+    use std::{io, fs::{self, File}, path::{Path, PathBuf}, sync::Mutex, os::unix::fs::MetadataExt};
+    use sha2::{Sha256, Digest};
+
+    // Stores each distinct file once, under
+    // base_path/.blobs/<first two hex digits>/<sha256 of its contents>. The
+    // path of each id, laid out as by LocalFileMap, is a hard link to its
+    // blob, so the link count of a blob is one more than the number of ids
+    // referencing it, and a blob with a link count of one is garbage.
+    //
+    // media_mixer never modifies a file in place, it renames a new file over
+    // the old one, so ids sharing a blob cannot affect each other. Blobs are
+    // also made read-only.
+    //
+    // base_path/.refs/<id> holds the hash each id was last stored as, so that
+    // the blob it replaced can be collected right away.
+    #[derive(Debug)]
+    pub struct ContentFileMap {
+        files: LocalFileMap,
+        blobs: PathBuf,
+        refs: PathBuf,
+        // Held while linking or collecting blobs.
+        lock: Mutex<()>,
+    }
+
+    impl ContentFileMap {
+        pub fn new(path: impl AsRef<Path>, extension: &'static str) -> Self {
+            Self::with_layout(path, extension, Layout::Flat)
+        }
+
+        pub fn with_layout(
+            path: impl AsRef<Path>,
+            extension: &'static str,
+            layout: Layout,
+        ) -> Self
+        {
+            Self {
+                files: LocalFileMap::with_layout(&path, extension, layout),
+                blobs: path.as_ref().join(".blobs"),
+                refs: path.as_ref().join(".refs"),
+                lock: Mutex::new(()),
+            }
+        }
+
+        pub fn migrate(&self, from: Layout) -> io::Result<usize> {
+            self.files.migrate(from)
+        }
+
+        pub fn base_path(&self) -> &Path {
+            self.files.base_path()
+        }
+
+        pub fn files(&self) -> io::Result<Vec<(u32, PathBuf)>> {
+            self.files.files()
+        }
+
+        // Stores every file that is not yet linked to a blob, such as those
+        // written before deduplication was enabled. Returns the number of
+        // files stored.
+        pub fn deduplicate(&self) -> io::Result<usize> {
+            let mut stored = 0;
+            for (key, path) in self.files.files()? {
+                if path.metadata()?.nlink() == 1 {
+                    self.store(&key, &path)?;
+                    stored += 1;
+                }
+            }
+            Ok(stored)
+        }
+
+        // Removes every blob that no id references. Returns the number of
+        // blobs removed.
+        pub fn collect_garbage(&self) -> io::Result<usize> {
+            let _guard = self.lock.lock().unwrap();
+            let dirs = match LocalFileMap::children(&self.blobs, true) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+                dirs => dirs?,
+            };
+            let mut removed = 0;
+            for dir in dirs {
+                for blob in LocalFileMap::children(&dir, false)? {
+                    if blob.metadata()?.nlink() == 1 {
+                        fs::remove_file(&blob)?;
+                        removed += 1;
+                    }
+                }
+            }
+            Ok(removed)
+        }
+
+        fn blob_path(&self, hash: &str) -> PathBuf {
+            self.blobs.join(&hash[..2]).join(hash)
+        }
+
+        fn ref_path(&self, key: u32) -> PathBuf {
+            self.refs.join(key.to_string())
+        }
+
+        // Records hash for key, returning the hash it replaced.
+        fn set_ref(&self, key: u32, hash: &str) -> io::Result<Option<String>> {
+            let previous = self.get_ref(key)?;
+            fs::create_dir_all(&self.refs)?;
+            let temp = self.refs.join(format!(".{}", key));
+            fs::write(&temp, hash)?;
+            fs::rename(&temp, self.ref_path(key))?;
+            Ok(previous)
+        }
+
+        // A ref that is not a sha256 hex digest, such as one truncated by a
+        // crash, is treated as missing.
+        fn get_ref(&self, key: u32) -> io::Result<Option<String>> {
+            match fs::read_to_string(self.ref_path(key)) {
+                Ok(hash) if is_hash(&hash) => Ok(Some(hash)),
+                Ok(_) => Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            }
+        }
+
+        // Removes the blob if nothing references it any more.
+        fn collect(&self, hash: &str) -> io::Result<()> {
+            let blob = self.blob_path(hash);
+            match blob.metadata() {
+                Ok(m) if m.nlink() == 1 => fs::remove_file(&blob),
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl FileMap for ContentFileMap {
+        type Error = io::Error;
+        fn get(&self, key: &u32) -> Result<PathBuf, Self::Error> {
+            self.files.get(key)
+        }
+        fn store(&self, key: &u32, path: &Path) -> Result<(), Self::Error> {
+            // Still linked to its blob, so nothing was written.
+            if path.metadata()?.nlink() > 1 {
+                return Ok(());
+            }
+            let hash = hash_file(path)?;
+            let _guard = self.lock.lock().unwrap();
+            let blob = self.blob_path(&hash);
+            if blob.is_file() {
+                let temp = path.with_file_name(format!(".{}.link", key));
+                let _ = fs::remove_file(&temp);
+                fs::hard_link(&blob, &temp)?;
+                fs::rename(&temp, path)?;
+            } else {
+                let mut permissions = path.metadata()?.permissions();
+                permissions.set_readonly(true);
+                fs::set_permissions(path, permissions)?;
+                fs::create_dir_all(blob.parent().unwrap())?;
+                fs::hard_link(path, &blob)?;
+            }
+            match self.set_ref(*key, &hash)? {
+                Some(previous) if previous != hash => self.collect(&previous),
+                _ => Ok(()),
+            }
+        }
+        fn release(&self, key: &u32) -> Result<(), Self::Error> {
+            let _guard = self.lock.lock().unwrap();
+            if let Some(hash) = self.get_ref(*key)? {
+                fs::remove_file(self.ref_path(*key))?;
+                self.collect(&hash)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn hash_file(path: &Path) -> io::Result<String> {
+        let mut hasher = Sha256::new();
+        io::copy(&mut File::open(path)?, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    fn is_hash(s: &str) -> bool {
+        s.len() == 64 && s.bytes().all(|b| match b {
+            b'0'..=b'9' | b'a'..=b'f' => true,
+            _ => false,
+        })
+    }
+
+    #[cfg(all(test, feature = "dedup"))]
+    mod tests {
+        use super::*;
+        use crate::core::test_dir::TestDir;
+
+        // Writes a new file over the path of key, as media_mixer does, and
+        // stores it.
+        fn write(map: &ContentFileMap, key: u32, contents: &str) {
+            let path = map.get(&key).unwrap();
+            let temp = path.with_file_name(format!(".{}.input", key));
+            fs::write(&temp, contents).unwrap();
+            fs::rename(&temp, &path).unwrap();
+            map.store(&key, &path).unwrap();
+        }
+
+        fn read(map: &ContentFileMap, key: u32) -> String {
+            fs::read_to_string(map.get(&key).unwrap()).unwrap()
+        }
+
+        fn blobs(map: &ContentFileMap) -> usize {
+            LocalFileMap::children(&map.blobs, true).unwrap()
+                .iter()
+                .map(|dir| LocalFileMap::children(dir, false).unwrap().len())
+                .sum()
+        }
+
+        #[test]
+        fn same_contents_share_a_blob() {
+            let dir = TestDir::new("same_contents_share_a_blob");
+            let map = ContentFileMap::new(dir.path(), "mkv");
+            write(&map, 1, "same");
+            write(&map, 2, "same");
+            assert_eq!(blobs(&map), 1);
+            let blob = map.blob_path(&map.get_ref(1).unwrap().unwrap());
+            assert_eq!(blob.metadata().unwrap().nlink(), 3);
+            assert_eq!(map.get_ref(2).unwrap(), map.get_ref(1).unwrap());
+        }
+
+        #[test]
+        fn replacing_keeps_shared_contents() {
+            let dir = TestDir::new("replacing_keeps_shared_contents");
+            let map = ContentFileMap::new(dir.path(), "mkv");
+            write(&map, 1, "same");
+            write(&map, 2, "same");
+            write(&map, 1, "new");
+            assert_eq!(read(&map, 1), "new");
+            assert_eq!(read(&map, 2), "same");
+            assert_eq!(blobs(&map), 2);
+            // Nothing references the old contents of 1 any more.
+            write(&map, 2, "newer");
+            assert_eq!(read(&map, 1), "new");
+            assert_eq!(blobs(&map), 2);
+        }
+
+        #[test]
+        fn collect_unreferenced_blobs() {
+            let dir = TestDir::new("collect_unreferenced_blobs");
+            let map = ContentFileMap::new(dir.path(), "mkv");
+            write(&map, 1, "shared");
+            write(&map, 2, "shared");
+            write(&map, 3, "own");
+            fs::remove_file(map.get(&1).unwrap()).unwrap();
+            map.release(&1).unwrap();
+            assert_eq!(blobs(&map), 2);
+            // Removed without being released, as after a crash.
+            fs::remove_file(map.get(&3).unwrap()).unwrap();
+            assert_eq!(map.collect_garbage().unwrap(), 1);
+            assert_eq!(blobs(&map), 1);
+            assert_eq!(read(&map, 2), "shared");
+            assert_eq!(map.collect_garbage().unwrap(), 0);
+        }
+
+        #[test]
+        fn invalid_ref_is_ignored() {
+            let dir = TestDir::new("invalid_ref_is_ignored");
+            let map = ContentFileMap::new(dir.path(), "mkv");
+            fs::create_dir_all(&map.refs).unwrap();
+            fs::write(map.ref_path(1), "a").unwrap();
+            assert_eq!(map.get_ref(1).unwrap(), None);
+            write(&map, 1, "contents");
+            assert!(map.get_ref(1).unwrap().is_some());
+            fs::write(map.ref_path(2), "").unwrap();
+            map.release(&2).unwrap();
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::{collections::{HashMap, HashSet}, fs, io::ErrorKind, path::{Path, PathBuf}};
+use serde::Serialize;
+use super::{Database, FileMap, FileTable, FileId, Error, Result, DB, FM};
+
+// Orphaned files are moved here on repair rather than removed. It is hidden,
+// so FileMap does not list it.
+const ORPHANS: &str = ".orphans";
+
+// Inconsistencies between database and the media root. With repair, each
+// kind is fixed as described below before the report is returned.
+#[derive(Debug, Default, Serialize)]
+pub struct FsckReport {
+    // Ids whose file does not exist. An id may be created without a file, so
+    // these are kept, and only their persisted stream hashes are removed.
+    pub missing_files: Vec<u32>,
+    // Files named after an id that does not exist, which would be taken as
+    // the file of that id were it created. Moved to base_path/.orphans,
+    // unless the id has been created since.
+    pub orphaned_files: Vec<PathBuf>,
+    // Aliases whose id does not exist. Removed.
+    pub dangling_aliases: Vec<DanglingAlias>,
+    // Ids whose file is empty, which File::len treats as having no length.
+    // The files are removed, keeping the ids, unless they have been written
+    // since.
+    pub empty_files: Vec<u32>,
+    pub repaired: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DanglingAlias {
+    pub alias: String,
+    pub id: u32,
+}
+
+pub fn fsck(
+    database: &Database,
+    file_table: &FileTable,
+    file_map: &FileMap,
+    repair: bool,
+) -> Result<FsckReport>
+{
+    let ids = database.list_ids()
+        .map_err(|e| Error::database_err(e))?;
+    let mut report = FsckReport::default();
+    for id in &ids {
+        let path = file_map.get(id)
+            .map_err(|e| Error::file_map_err(e))?;
+        match path.metadata() {
+            Ok(m) if m.len() == 0 => report.empty_files.push(*id),
+            Ok(_) => (),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                report.missing_files.push(*id)
+            },
+            Err(e) => return Err(Error::FileSystemError(e)),
+        };
+    }
+    let ids: HashSet<u32> = ids.into_iter().collect();
+    report.orphaned_files = file_map.files()
+        .map_err(|e| Error::FileSystemError(e))?
+        .into_iter()
+        .filter(|(id, _)| !ids.contains(id))
+        .map(|(_, path)| path)
+        .collect();
+    report.dangling_aliases = database.list_dangling_aliases()
+        .map_err(|e| Error::database_err(e))?
+        .into_iter()
+        .map(|(alias, id)| DanglingAlias { alias: alias, id: id })
+        .collect();
+    if repair {
+        report.repair(database, file_table, file_map)?;
+    }
+    Ok(report)
+}
+
+impl FsckReport {
+    fn repair(
+        &mut self,
+        database: &Database,
+        file_table: &FileTable,
+        file_map: &FileMap,
+    ) -> Result<()>
+    {
+        for id in &self.missing_files {
+            database.remove_stream_hashes(*id)
+                .map_err(|e| Error::database_err(e))?;
+        }
+        // The checks above may have taken long enough for an id to be
+        // created, or a file to be written, since.
+        for path in &self.orphaned_files {
+            let id = path.file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u32>().ok());
+            if let Some(id) = id {
+                if database.id_exists(id).map_err(|e| Error::database_err(e))? {
+                    continue;
+                }
+            }
+            move_orphan(path, &file_map.base_path().join(ORPHANS))
+                .map_err(|e| Error::FileSystemError(e))?;
+        }
+        let mut aliases: HashMap<u32, Vec<String>> = HashMap::new();
+        for dangling in &self.dangling_aliases {
+            aliases.entry(dangling.id)
+                .or_default()
+                .push(dangling.alias.clone());
+        }
+        for (id, aliases) in aliases {
+            database.remove_aliases(aliases, id)
+                .map_err(|e| Error::database_err(e))?;
+        }
+        for id in &self.empty_files {
+            let id = match FileId::from_id(*id, database, file_table, file_map) {
+                Ok(id) => id,
+                // Removed since it was checked.
+                Err(Error::IdNotFound(_)) => continue,
+                Err(e) => return Err(e),
+            };
+            id.remove_file_if(|file| match file.len() {
+                Ok(None) => Ok(true),
+                Ok(Some(_)) | Err(Error::FileNotFound) => Ok(false),
+                Err(e) => Err(e),
+            })?;
+        }
+        self.repaired = true;
+        Ok(())
+    }
+}
+
+// Keeps the file name, adding a counter if an orphan with the same name was
+// moved before.
+fn move_orphan(path: &Path, orphans: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(orphans)?;
+    let name = path.file_name().unwrap_or_default();
+    let mut target = orphans.join(name);
+    let mut n = 0;
+    while target.exists() {
+        n += 1;
+        let mut numbered = name.to_owned();
+        numbered.push(format!(".{}", n));
+        target = orphans.join(numbered);
+    }
+    fs::rename(path, target)
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::core::test_dir::TestDir;
+
+    // Repair runs on what fsck found earlier, by which time the id of an
+    // orphan may have been created, and an empty file written.
+    #[test]
+    fn repair_rechecks() {
+        let dir = TestDir::new("repair_rechecks");
+        let database = Database::new(dir.path().join("db.sqlite")).unwrap();
+        let file_table = FileTable::new();
+        let file_map = FileMap::new(dir.path().join("media"), "mkv");
+        fs::create_dir_all(dir.path().join("media")).unwrap();
+        let written = FileId::new(&database, &file_table, &file_map).unwrap();
+        let empty = FileId::new(&database, &file_table, &file_map).unwrap();
+        let path = |id: u32| file_map.base_path().join(format!("{}.mkv", id));
+        fs::write(path(*written), b"not empty").unwrap();
+        fs::write(path(*empty), b"").unwrap();
+        fs::write(path(*empty + 1), b"orphan").unwrap();
+        let report = fsck(&database, &file_table, &file_map, false).unwrap();
+        assert_eq!(report.empty_files, vec![*empty]);
+        assert_eq!(report.orphaned_files, vec![path(*empty + 1)]);
+        let created = FileId::new(&database, &file_table, &file_map).unwrap();
+        assert_eq!(*created, *empty + 1);
+        let mut report = FsckReport {
+            empty_files: vec![*written, *empty],
+            ..report
+        };
+        report.repair(&database, &file_table, &file_map).unwrap();
+        assert!(path(*written).is_file());
+        assert!(!path(*empty).exists());
+        assert!(path(*created).is_file());
+        assert!(!file_map.base_path().join(ORPHANS).exists());
+    }
+}
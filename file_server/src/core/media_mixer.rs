@@ -1,58 +1,705 @@
-use std::{io::{self, Read}, path::{Path, PathBuf}, sync::RwLock, collections::HashMap, process::{Command, Stdio}};
+use std::{io::{self, Read}, path::{Path, PathBuf}, sync::{Arc, RwLock, atomic::{AtomicUsize, Ordering}}, collections::HashMap, process::{Command, Stdio, ExitStatus}, fs::{self, File as StdFile}, ffi::{OsStr, OsString}};
 use lazy_static::lazy_static;
+use serde::Deserialize;
+use super::probe::{Probe, Stream, StreamKind, Chapter};
 
 lazy_static! {
-    static ref FLOCK: RwLock<HashMap<PathBuf, RwLock<()>>> = RwLock::new(
+    static ref FLOCK: RwLock<HashMap<PathBuf, Arc<RwLock<()>>>> = RwLock::new(
         HashMap::new()
     );
 }
 
+// Distinguishes concurrent extractions of the same stream.
+static EXTRACTIONS: AtomicUsize = AtomicUsize::new(0);
+// Distinguishes concurrent uploads to the same file.
+static UPLOADS: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(Debug)]
 pub enum Error {
     ProcessError(io::Error),
+    ExitError(ExitStatus),
+    FileSystemError(io::Error),
     InvalidOutput(serde_json::Error),
+    InvalidHashOutput(String),
+    UploadTooLarge(u64),
+    InsufficientSpace,
 }
 
-pub fn mux_file(
+// Bounds on what stage_file accepts, and on the space mux_file may use.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UploadLimits {
+    // The most bytes a single upload may have, if any.
+    pub max_bytes: Option<u64>,
+    // The space that must be left free on the filesystem of the target once
+    // both the upload and the file muxed from it are written.
+    pub min_free: u64,
+}
+
+// Containers that mux_file may choose for a new file, by extension.
+pub const CONTAINER_EXTENSIONS: &[&str] = &["mkv", "mp4", "webm"];
+
+const WEBM_CODECS: &[&str] = &["vp8", "vp9", "av1", "opus", "vorbis", "webvtt"];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct StreamMetadata {
+    pub language: Option<String>,
+    pub title: Option<String>,
+    pub default: Option<bool>,
+    pub forced: Option<bool>,
+}
+
+#[derive(Debug)]
+pub struct ExtractedStream {
+    pub file: StdFile,
+    pub extension: &'static str,
+}
+
+// An upload copied next to the file it is for by stage_file, which mux_file
+// merges into that file. Removed when dropped.
+pub struct StagedFile {
+    input: TempFile,
+    len: u64,
+}
+
+// Removes the file at the wrapped path when dropped, unless it has already
+// been renamed away.
+struct TempFile(PathBuf);
+
+#[derive(Deserialize)]
+struct StreamProbe {
+    #[serde(default)]
+    streams: Vec<StreamEntry>,
+    format: Option<FormatEntry>,
+}
+
+#[derive(Deserialize)]
+struct StreamEntry {
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FormatEntry {
+    format_name: String,
+}
+
+// Copies source next to target, to be passed to mux_file. The lock for
+// target is not taken, so that a slow upload does not hold up other requests
+// for it.
+pub fn stage_file(
     source: impl Read,
     target: impl AsRef<Path>,
-) -> Result<Option<()>, Error>
+    limits: &UploadLimits,
+) -> Result<StagedFile, Error>
 {
-    unimplemented!()
+    let target = target.as_ref();
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| Error::FileSystemError(e))?;
+    }
+    let tag = format!("input-{}", UPLOADS.fetch_add(1, Ordering::Relaxed));
+    let input = TempFile(temp_path(target, &tag, None));
+    let len = stage(source, &input.0, limits)?;
+    Ok(StagedFile { input: input, len: len })
+}
+
+// Merges every audio, video and subtitle stream from staged into the file at
+// target, creating it if it does not exist. A new file keeps the container
+// of staged if it is one of CONTAINER_EXTENSIONS, replacing the extension of
+// target, and otherwise uses the extension of target.
+//
+// target is only replaced once the new file is complete, so a failed upload
+// leaves it untouched.
+//
+// Returns the path that was written, or None if staged has no media streams.
+pub fn mux_file(
+    staged: StagedFile,
+    target: impl AsRef<Path>,
+    limits: &UploadLimits,
+) -> Result<Option<PathBuf>, Error>
+{
+    let target = target.as_ref();
+    let lock = path_lock(target);
+    let _guard = lock.write().unwrap();
+    let input = staged.input;
+    let probe = match probe_streams(&input.0) {
+        Ok(probe) => probe,
+        Err(Error::ExitError(_)) => return Ok(None), // ffprobe could not read it
+        Err(e) => return Err(e),
+    };
+    if !probe.streams.iter().any(|s| s.is_media()) {
+        return Ok(None);
+    }
+    let exists = target.is_file();
+    // Copying streams makes the output about as large as its inputs.
+    let existing = match exists {
+        true => target.metadata()
+            .map_err(|e| Error::FileSystemError(e))?
+            .len(),
+        false => 0,
+    };
+    if free_space(target, limits)? < staged.len + existing {
+        return Err(Error::InsufficientSpace);
+    }
+    let destination = match probe.container_extension() {
+        Some(ext) if !exists => target.with_extension(ext),
+        _ => target.to_owned(),
+    };
+    let output = TempFile(
+        temp_path(target, "mux", destination.extension())
+    );
+    let mut command = ffmpeg();
+    if exists {
+        command
+            .arg("-i").arg(target)
+            .arg("-i").arg(&input.0)
+            .arg("-map").arg("0")
+            .arg("-map").arg("1:v?")
+            .arg("-map").arg("1:a?")
+            .arg("-map").arg("1:s?");
+    } else {
+        command
+            .arg("-i").arg(&input.0)
+            .arg("-map").arg("0:v?")
+            .arg("-map").arg("0:a?")
+            .arg("-map").arg("0:s?");
+    }
+    run(command
+        .arg("-c").arg("copy")
+        .arg(&output.0)
+    )?;
+    output.persist(&destination)?;
+    Ok(Some(destination))
 }
 
+// Rewrites the file at target without the streams at the given indexes.
 pub fn partial_demux_file(
-    target_hashes: &[usize],
+    indexes: &[usize],
     target: impl AsRef<Path>,
 ) -> Result<(), Error>
 {
-    unimplemented!()
+    if indexes.is_empty() {
+        return Ok(());
+    }
+    let target = target.as_ref();
+    let lock = path_lock(target);
+    let _guard = lock.write().unwrap();
+    remux(target, "demux", |command| {
+        command.arg("-map").arg("0");
+        for i in indexes {
+            command.arg("-map").arg(format!("-0:{}", i));
+        }
+        command
+            .arg("-map_metadata").arg("0")
+            .arg("-map_chapters").arg("0");
+    })
 }
 
+// Sets the given metadata on the streams at the given indexes, leaving
+// anything that is None unchanged.
+pub fn edit_streams(
+    indexes: &[usize],
+    metadata: &StreamMetadata,
+    target: impl AsRef<Path>,
+) -> Result<(), Error>
+{
+    let target = target.as_ref();
+    let lock = path_lock(target);
+    let _guard = lock.write().unwrap();
+    let current = run_probe(target)?;
+    remux(target, "edit", |command| {
+        command
+            .arg("-map").arg("0")
+            .arg("-map_metadata").arg("0")
+            .arg("-map_chapters").arg("0");
+        for &i in indexes {
+            if let Some(language) = &metadata.language {
+                command
+                    .arg(format!("-metadata:s:{}", i))
+                    .arg(format!("language={}", language));
+            }
+            if let Some(title) = &metadata.title {
+                command
+                    .arg(format!("-metadata:s:{}", i))
+                    .arg(format!("title={}", title));
+            }
+            if metadata.default.is_none() && metadata.forced.is_none() {
+                continue;
+            }
+            // -disposition replaces every flag, so start from the current ones.
+            let mut disposition = current.streams.iter()
+                .find(|s| s.index == i)
+                .map(|s| s.disposition.clone())
+                .unwrap_or_default();
+            disposition.default = metadata.default
+                .unwrap_or(disposition.default);
+            disposition.forced = metadata.forced
+                .unwrap_or(disposition.forced);
+            command
+                .arg(format!("-disposition:{}", i))
+                .arg(disposition.to_ffmpeg());
+        }
+        if is_matroska(target) {
+            // Otherwise the muxer picks its own default streams.
+            command.arg("-default_mode").arg("passthrough");
+        }
+    })
+}
+
+// Replaces every chapter in the file at target. An empty slice removes them.
+pub fn write_chapters(
+    chapters: &[Chapter],
+    target: impl AsRef<Path>,
+) -> Result<(), Error>
+{
+    let target = target.as_ref();
+    let lock = path_lock(target);
+    let _guard = lock.write().unwrap();
+    if chapters.is_empty() {
+        return remux(target, "chapters", |command| {
+            command
+                .arg("-map").arg("0")
+                .arg("-map_metadata").arg("0")
+                .arg("-map_chapters").arg("-1");
+        });
+    }
+    let metadata = TempFile(
+        temp_path(target, "metadata", Some(OsStr::new("txt")))
+    );
+    fs::write(&metadata.0, ffmetadata_chapters(chapters))
+        .map_err(|e| Error::FileSystemError(e))?;
+    remux(target, "chapters", |command| {
+        command
+            .arg("-f").arg("ffmetadata")
+            .arg("-i").arg(&metadata.0)
+            .arg("-map").arg("0")
+            .arg("-map_metadata").arg("0")
+            .arg("-map_chapters").arg("1");
+    })
+}
+
+// Copies the stream at index into a standalone file. The file is unlinked
+// once opened, so it is cleaned up as soon as the handle is dropped.
+//
+// Returns None if the stream has no standalone container.
+pub fn extract_stream(
+    index: usize,
+    target: impl AsRef<Path>,
+) -> Result<Option<ExtractedStream>, Error>
+{
+    let target = target.as_ref();
+    let lock = path_lock(target);
+    let _guard = lock.read().unwrap();
+    let probe = run_probe(target)?;
+    let (extension, codec) = match probe.streams.iter()
+        .find(|s| s.index == index)
+        .and_then(|s| standalone_container(s))
+    {
+        Some(container) => container,
+        None => return Ok(None),
+    };
+    let tag = format!(
+        "extract-{}-{}",
+        index,
+        EXTRACTIONS.fetch_add(1, Ordering::Relaxed),
+    );
+    let output = TempFile(temp_path(target, &tag, Some(OsStr::new(extension))));
+    run(ffmpeg()
+        .arg("-i").arg(target)
+        .arg("-map").arg(format!("0:{}", index))
+        .arg("-map_metadata").arg("-1")
+        .arg("-map_chapters").arg("-1")
+        .arg("-c").arg(codec.unwrap_or("copy"))
+        .arg(&output.0)
+    )?;
+    let file = StdFile::open(&output.0)
+        .map_err(|e| Error::FileSystemError(e))?;
+    Ok(Some(ExtractedStream { file: file, extension: extension }))
+}
+
+// Removes the file at target. Does nothing if it does not exist.
+pub fn remove_file(target: impl AsRef<Path>) -> Result<(), Error> {
+    let target = target.as_ref();
+    let lock = path_lock(target);
+    let _guard = lock.write().unwrap();
+    match fs::remove_file(target) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(
+            Error::FileSystemError(e)
+        ),
+        _ => Ok(()),
+    }
+}
+
+// Hashes the packet payload of every stream in the file, in index order.
+//
+// Only the demuxed packets are hashed, so the same elementary stream hashes
+// identically regardless of the container it is stored in.
 pub fn try_hash_file(
     path: impl AsRef<Path>,
 ) -> Result<Vec<String>, Error>
 {
-    unimplemented!()
+    let path = path.as_ref();
+    let lock = path_lock(path);
+    let _guard = lock.read().unwrap();
+    let output = ffmpeg()
+        .arg("-i").arg(path)
+        .arg("-map").arg("0")
+        .arg("-c").arg("copy")
+        .arg("-copy_unknown")
+        .arg("-f").arg("streamhash")
+        .arg("-hash").arg("sha256")
+        .arg("-")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::ProcessError(e))?
+        .wait_with_output()
+        .map_err(|e| Error::ProcessError(e))?;
+    if !output.status.success() {
+        return Err(Error::ExitError(output.status));
+    }
+    parse_stream_hashes(&String::from_utf8_lossy(&output.stdout))
 }
 
-pub fn json_probe(
+pub fn probe(
     path: impl AsRef<Path>,
-) -> Result<serde_json::Value, Error>
+) -> Result<Probe, Error>
 {
-    let bytes = Command::new("ffprobe")
-        .arg(path.as_ref())
+    let path = path.as_ref();
+    let lock = path_lock(path);
+    let _guard = lock.read().unwrap();
+    run_probe(path)
+}
+
+// The caller must hold a lock for path.
+fn run_probe(path: &Path) -> Result<Probe, Error> {
+    let output = Command::new("ffprobe")
+        .arg(path)
         .arg("-loglevel").arg("quiet")
-        .arg("-show_versions")
         .arg("-show_format")
         .arg("-show_streams")
         .arg("-show_chapters")
-        .arg("-of").arg("json='c=0'")
+        .arg("-of").arg("json")
         .stdout(Stdio::piped())
         .spawn()
         .map_err(|e| Error::ProcessError(e))?
         .wait_with_output()
+        .map_err(|e| Error::ProcessError(e))?;
+    if !output.status.success() {
+        return Err(Error::ExitError(output.status));
+    }
+    Probe::from_ffprobe(&output.stdout)
+        .map_err(|e| Error::InvalidOutput(e))
+}
+
+fn probe_streams(path: impl AsRef<Path>) -> Result<StreamProbe, Error> {
+    let output = Command::new("ffprobe")
+        .arg(path.as_ref())
+        .arg("-loglevel").arg("quiet")
+        .arg("-show_entries")
+        .arg("stream=codec_type,codec_name:format=format_name")
+        .arg("-of").arg("json")
+        .stdout(Stdio::piped())
+        .spawn()
         .map_err(|e| Error::ProcessError(e))?
-        .stdout;
-    serde_json::from_slice(&bytes).map_err(|e| Error::InvalidOutput(e))
-}
\ No newline at end of file
+        .wait_with_output()
+        .map_err(|e| Error::ProcessError(e))?;
+    if !output.status.success() {
+        return Err(Error::ExitError(output.status));
+    }
+    serde_json::from_slice(&output.stdout)
+        .map_err(|e| Error::InvalidOutput(e))
+}
+
+// Parses streamhash muxer output, one "<index>,<type>,<ALGO>=<hex>" per line.
+fn parse_stream_hashes(output: &str) -> Result<Vec<String>, Error> {
+    let mut hashes: Vec<(usize, String)> = Vec::new();
+    for line in output.lines().filter(|l| !l.is_empty()) {
+        let mut fields = line.splitn(3, ',');
+        let index = fields.next().and_then(|i| i.parse::<usize>().ok());
+        let hash = fields.nth(1)
+            .and_then(|h| h.splitn(2, '=').nth(1));
+        match (index, hash) {
+            (Some(i), Some(h)) => hashes.push((i, h.to_lowercase())),
+            _ => return Err(Error::InvalidHashOutput(line.to_string())),
+        }
+    }
+    hashes.sort_by_key(|(i, _)| *i);
+    Ok(hashes.into_iter().map(|(_, h)| h).collect())
+}
+
+// Every path shares one lock for as long as the process runs, so that writes
+// to the same file are serialized no matter which FileTable entry they use.
+fn path_lock(path: &Path) -> Arc<RwLock<()>> {
+    if let Some(lock) = FLOCK.read().unwrap().get(path) {
+        return lock.clone();
+    }
+    FLOCK.write().unwrap()
+        .entry(path.to_owned())
+        .or_default()
+        .clone()
+}
+
+
+fn ffmpeg() -> Command {
+    let mut command = Command::new("ffmpeg");
+    command
+        .arg("-nostdin")
+        .arg("-y")
+        .arg("-loglevel").arg("error");
+    command
+}
+
+fn run(command: &mut Command) -> Result<(), Error> {
+    let status = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .map_err(|e| Error::ProcessError(e))?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(Error::ExitError(status)),
+    }
+}
+
+// Copies target into a new file of the same container with the arguments
+// from args, which must include the -map options, then replaces target with
+// it. The caller must hold the write lock for target.
+fn remux(
+    target: &Path,
+    tag: &str,
+    args: impl FnOnce(&mut Command),
+) -> Result<(), Error>
+{
+    let output = TempFile(temp_path(target, tag, target.extension()));
+    let mut command = ffmpeg();
+    command.arg("-i").arg(target);
+    args(&mut command);
+    run(command
+        .arg("-c").arg("copy")
+        .arg(&output.0)
+    )?;
+    output.persist(target)
+}
+
+// The extension to extract a stream into, and the codec to convert it to if
+// it cannot be copied into that container as is.
+fn standalone_container(
+    stream: &Stream,
+) -> Option<(&'static str, Option<&'static str>)>
+{
+    let codec = stream.codec.as_ref().map(|s| s.as_str());
+    match (stream.kind, codec) {
+        (StreamKind::Subtitle, Some("subrip")) => Some(("srt", None)),
+        (StreamKind::Subtitle, Some("ass")) => Some(("ass", None)),
+        (StreamKind::Subtitle, Some("ssa")) => Some(("ass", None)),
+        (StreamKind::Subtitle, Some("webvtt")) => Some(("vtt", None)),
+        (StreamKind::Subtitle, Some("mov_text")) => Some(("srt", Some("srt"))),
+        (StreamKind::Subtitle, Some("hdmv_pgs_subtitle")) => Some(("sup", None)),
+        (StreamKind::Subtitle, _) => Some(("mks", None)),
+        (StreamKind::Audio, _) => Some(("mka", None)),
+        (StreamKind::Video, _) if !stream.disposition.attached_pic => Some(
+            ("mkv", None)
+        ),
+        _ => None,
+    }
+}
+
+// https://ffmpeg.org/ffmpeg-formats.html#Metadata-1
+fn ffmetadata_chapters(chapters: &[Chapter]) -> String {
+    let escape = |s: &str| s.chars()
+        .fold(String::with_capacity(s.len()), |mut escaped, c| {
+            if let '=' | ';' | '#' | '\\' | '\n' = c {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+            escaped
+        });
+    let mut metadata = String::from(";FFMETADATA1\n");
+    for chapter in chapters {
+        metadata.push_str("[CHAPTER]\nTIMEBASE=1/1000\n");
+        metadata.push_str(&format!(
+            "START={}\nEND={}\n",
+            (chapter.start * 1000.0).round() as u64,
+            (chapter.end * 1000.0).round() as u64,
+        ));
+        if let Some(title) = &chapter.title {
+            metadata.push_str(&format!("title={}\n", escape(title)));
+        }
+    }
+    metadata
+}
+
+fn is_matroska(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("mkv") | Some("mka") | Some("mks") | Some("webm") => true,
+        _ => false,
+    }
+}
+
+// Copies source to path, stopping once it is larger than limits allow or than
+// the free space left next to path. Returns the number of bytes copied.
+fn stage(
+    source: impl Read,
+    path: &Path,
+    limits: &UploadLimits,
+) -> Result<u64, Error>
+{
+    let free = free_space(path, limits)?;
+    let limit = limits.max_bytes.map_or(free, |max| max.min(free));
+    let mut file = StdFile::create(path)
+        .map_err(|e| Error::FileSystemError(e))?;
+    // One byte past the limit tells an upload at the limit from a larger one.
+    let copied = io::copy(&mut source.take(limit.saturating_add(1)), &mut file)
+        .map_err(|e| Error::FileSystemError(e))?;
+    if copied > limit {
+        return match limits.max_bytes {
+            Some(max) if max <= free => Err(Error::UploadTooLarge(max)),
+            _ => Err(Error::InsufficientSpace),
+        };
+    }
+    file.sync_all()
+        .map_err(|e| Error::FileSystemError(e))?;
+    Ok(copied)
+}
+
+// The space available to new files next to path, less limits.min_free.
+fn free_space(path: &Path, limits: &UploadLimits) -> Result<u64, Error> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    fs2::available_space(dir)
+        .map(|available| available.saturating_sub(limits.min_free))
+        .map_err(|e| Error::FileSystemError(e))
+}
+
+// A hidden sibling of target, so that renaming it over target is atomic.
+// The extension is kept so that ffmpeg can pick the output container.
+fn temp_path(
+    target: &Path,
+    tag: &str,
+    extension: Option<&OsStr>,
+) -> PathBuf
+{
+    let mut name = OsString::from(".");
+    name.push(target.file_stem().unwrap_or_default());
+    name.push(".");
+    name.push(tag);
+    if let Some(ext) = extension {
+        name.push(".");
+        name.push(ext);
+    }
+    target.with_file_name(name)
+}
+
+impl StreamProbe {
+    // ffprobe reports both Matroska and WebM as "matroska,webm", so WebM is
+    // only chosen when every media stream is allowed in it.
+    fn container_extension(&self) -> Option<&'static str> {
+        let names: Vec<&str> = self.format.as_ref()?
+            .format_name
+            .split(',')
+            .collect();
+        if names.contains(&"mp4") {
+            return Some("mp4");
+        }
+        if !names.contains(&"matroska") {
+            return None;
+        }
+        let webm = self.streams.iter()
+            .filter(|s| s.is_media())
+            .all(|s| s.codec_name.as_ref()
+                .map_or(false, |c| WEBM_CODECS.contains(&c.as_str()))
+            );
+        match webm {
+            true => Some("webm"),
+            false => Some("mkv"),
+        }
+    }
+}
+
+impl StreamEntry {
+    fn is_media(&self) -> bool {
+        match self.codec_type.as_ref().map(|s| s.as_str()) {
+            Some("video") | Some("audio") | Some("subtitle") => true,
+            _ => false,
+        }
+    }
+}
+
+impl TempFile {
+    fn persist(self, target: &Path) -> Result<(), Error> {
+        StdFile::open(&self.0)
+            .and_then(|f| f.sync_all())
+            .map_err(|e| Error::FileSystemError(e))?;
+        fs::rename(&self.0, target)
+            .map_err(|e| Error::FileSystemError(e))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::test_dir::TestDir;
+
+    #[test]
+    fn stream_hashes_by_index() {
+        let output = "1,a,SHA256=ABC123\n\n0,v,SHA256=def456\n2,s,SHA256=a=b\n";
+        assert_eq!(
+            parse_stream_hashes(output).unwrap(),
+            vec!["def456", "abc123", "a=b"],
+        );
+        assert!(parse_stream_hashes("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_stream_hashes() {
+        for line in &["garbage", "x,v,SHA256=abc", "0,v", "0,v,abc"] {
+            match parse_stream_hashes(&format!("0,v,SHA256=abc\n{}\n", line)) {
+                Err(Error::InvalidHashOutput(l)) => assert_eq!(&l, line),
+                r => panic!("{:?} parsed as {:?}", line, r),
+            };
+        }
+    }
+
+    #[test]
+    fn chapters_are_escaped() {
+        let chapters = vec![
+            Chapter { start: 0.0, end: 61.5, title: None },
+            Chapter {
+                start: 61.5,
+                end: 123.4567,
+                title: Some("a=b; #c \\d\ne".to_string()),
+            },
+        ];
+        assert_eq!(
+            ffmetadata_chapters(&chapters),
+            ";FFMETADATA1\n\
+            [CHAPTER]\nTIMEBASE=1/1000\nSTART=0\nEND=61500\n\
+            [CHAPTER]\nTIMEBASE=1/1000\nSTART=61500\nEND=123457\n\
+            title=a\\=b\\; \\#c \\\\d\\\ne\n",
+        );
+        assert_eq!(ffmetadata_chapters(&[]), ";FFMETADATA1\n");
+    }
+
+    #[test]
+    fn stage_up_to_max_bytes() {
+        let dir = TestDir::new("stage_up_to_max_bytes");
+        let path = dir.path().join(".1.input");
+        let n = 4096;
+        let limits = |max| UploadLimits { max_bytes: Some(max), min_free: 0 };
+        assert_eq!(
+            stage(io::repeat(0).take(n), &path, &limits(n)).unwrap(),
+            n,
+        );
+        assert_eq!(path.metadata().unwrap().len(), n);
+        match stage(io::repeat(0).take(n), &path, &limits(n - 1)) {
+            Err(Error::UploadTooLarge(max)) => assert_eq!(max, n - 1),
+            r => panic!("{} bytes over a limit of {} gave {:?}", n, n - 1, r),
+        };
+    }
+}
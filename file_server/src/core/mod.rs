@@ -1,4 +1,4 @@
-use std::{time::SystemTime, path::{PathBuf}, io::{Read, ErrorKind}, fs::File as StdFile, sync::{RwLock, RwLockReadGuard}};
+use std::{time::SystemTime, path::{PathBuf}, io::{Read, Seek, SeekFrom, ErrorKind}, fs::File as StdFile, sync::{RwLock, RwLockReadGuard}};
 use {database::Database as DB, file_map::FileMap as FM};
 use chashmap::{CHashMap, ReadGuard, WriteGuard};
 use derive_more::{Deref, DerefMut};
@@ -7,10 +7,28 @@ mod media_mixer;
 mod database;
 mod file_map;
 mod error;
+mod probe;
+mod watcher;
+mod fsck;
 
 pub use error::Error;
+pub use probe::{Probe, Format, Stream, StreamKind, Disposition, Chapter};
+pub use media_mixer::{StreamMetadata, ExtractedStream, UploadLimits};
+pub use file_map::local_file_map::Layout;
+pub use watcher::watch;
+pub use fsck::{fsck, FsckReport};
+#[cfg(not(feature = "dedup"))]
 pub type FileMap = file_map::local_file_map::LocalFileMap;
+#[cfg(feature = "dedup")]
+pub type FileMap = file_map::content_file_map::ContentFileMap;
+#[cfg(all(feature = "sqlite", feature = "postgres"))]
+compile_error!("the sqlite and postgres features are mutually exclusive");
+#[cfg(not(any(feature = "sqlite", feature = "postgres")))]
+compile_error!("either the sqlite or the postgres feature must be enabled");
+#[cfg(feature = "sqlite")]
 pub type Database = database::sqlite_database::SqliteDatabase;
+#[cfg(feature = "postgres")]
+pub type Database = database::postgres_database::PgDatabase;
 
 type Result<T> = std::result::Result<T, Error>;
 
@@ -25,6 +43,7 @@ pub struct FileId<'db, 't, 'm> {
 
 #[derive(Debug)]
 pub struct File {
+    id: u32,
     path: PathBuf,
     streams: RwLock<Option<(Vec<String>, u64)>>,
 }
@@ -57,6 +76,34 @@ struct Reader<'t> {
 
 struct Streams<'a>(RwLockReadGuard<'a, Option<(Vec<String>, u64)>>);
 
+// Returns up to limit ids greater than after, in order, with their aliases.
+// If has_alias is given, only ids with or without aliases are returned. If
+// namespace is given, only ids with an alias in it are returned.
+pub fn list_page(
+    database: &Database,
+    after: Option<u32>,
+    limit: u32,
+    has_alias: Option<bool>,
+    namespace: Option<&str>,
+) -> Result<Vec<(u32, Vec<String>)>>
+{
+    database.list_page(after, limit, has_alias, namespace)
+        .map_err(|e| Error::database_err(e))
+}
+
+// Returns up to limit (alias, id) pairs, ordered by alias, whose alias is in
+// namespace, if given, and whose name starts with prefix.
+pub fn search_aliases(
+    database: &Database,
+    namespace: Option<&str>,
+    prefix: &str,
+    limit: u32,
+) -> Result<Vec<(String, u32)>>
+{
+    database.search_aliases(namespace, prefix, limit)
+        .map_err(|e| Error::database_err(e))
+}
+
 impl<'db, 't, 'm> FileId<'db, 't, 'm> {
     pub fn new(
         database: &'db Database,
@@ -168,32 +215,88 @@ impl<'db, 't, 'm> FileId<'db, 't, 'm> {
             .map_err(|e| Error::database_err(e))
     }
 
+    // Removes the file and its FileTable entry, then the id and its aliases.
+    // An id without a file is harmless, while a file without an id is an
+    // orphan, so if removing the file fails the id is kept.
+    pub fn remove(self) -> Result<()> {
+        self.remove_file()?;
+        self.database.remove_id(*self)
+            .map_err(|e| Error::database_err(e))?
+            .ok_or(Error::IdNotFound(*self))
+    }
+
+    // Removes the file and its FileTable entry, keeping the id and its
+    // aliases. The entry would otherwise keep the path of the removed file,
+    // which FileMap may no longer return.
+    pub fn remove_file(&self) -> Result<()> {
+        self.remove_file_if(|_| Ok(true)).map(|_| ())
+    }
+
+    // As remove_file, but only if condition holds for the file under the
+    // write lock. Returns whether the file was removed.
+    fn remove_file_if(
+        &self,
+        condition: impl FnOnce(&File) -> Result<bool>,
+    ) -> Result<bool>
+    {
+        let mut file = self.rw_file()?;
+        if !condition(&file)? {
+            return Ok(false);
+        }
+        file.remove()?;
+        self.file_map.release(self)
+            .map_err(|e| Error::file_map_err(e))?;
+        std::mem::drop(file);
+        self.file_table.remove(self);
+        Ok(true)
+    }
+
+    // Merges the streams of file into the file of the id. The upload is
+    // staged before the write lock on the file is taken, so that reads of
+    // it are not held up while the upload streams in.
+    pub fn with_file(
+        &self,
+        file: impl Read,
+        limits: &UploadLimits,
+    ) -> Result<()>
+    {
+        let staged = media_mixer::stage_file(file, self.path()?, limits)?;
+        let mut file = self.rw_file()?;
+        let muxed = media_mixer::mux_file(staged, &file.path, limits)?;
+        file.path = muxed.ok_or(Error::InvalidMediaFile)?;
+        file.store(self.file_map)
+    }
+
     // Ensures that FileMap and FileTable return the same path, then returns
     // a file for reading only.
+    //
+    // A guard on an entry must be dropped before inserting into its bucket,
+    // or the insert waits on the guard forever. The entry may also be
+    // replaced or removed in between, hence the loop.
     pub fn ro_file(&self) -> Result<ROFile<'t>> {
         let path = self.path()?;
-        let inner = match self.file_table.get(self) {
-            Some(f) if f.path == path => f,
-            _ => {
-                self.insert_file(path);
-                self.file_table.get(self).unwrap()
-            },
-        };
-        Ok(ROFile { inner: inner })
+        loop {
+            let inner = self.file_table.get(self)
+                .filter(|f| f.path == path);
+            match inner {
+                Some(inner) => return Ok(ROFile { inner: inner }),
+                None => self.insert_file(path.clone()),
+            };
+        }
     }
 
     // Ensures that FileMap and FileTable return the same path, then returns
-    // a file for reading or writing.
+    // a file for reading or writing. See ro_file.
     pub fn rw_file(&self) -> Result<RWFile<'t>> {
         let path = self.path()?;
-        let inner = match self.file_table.get_mut(self) {
-            Some(f) if f.path == path => f,
-            _ => {
-                self.insert_file(path);
-                self.file_table.get_mut(self).unwrap()
-            },
-        };
-        Ok(RWFile { inner: inner })
+        loop {
+            let inner = self.file_table.get_mut(self)
+                .filter(|f| f.path == path);
+            match inner {
+                Some(inner) => return Ok(RWFile { inner: inner }),
+                None => self.insert_file(path.clone()),
+            };
+        }
     }
 
     fn path(&self) -> Result<PathBuf> {
@@ -203,45 +306,116 @@ impl<'db, 't, 'm> FileId<'db, 't, 'm> {
 
     fn insert_file(&self, path: PathBuf) {
         self.file_table.insert(**self, File {
+            id: **self,
             path: path,
             streams: RwLock::new(None),
         });
     }
 }
 
+impl FileTable {
+    pub fn new() -> Self {
+        Self { inner: CHashMap::new() }
+    }
+}
+
 impl File {
-    // return None if the file has no streams
-    pub fn with_file(&mut self, file: impl Read) -> Result<()> {
-        media_mixer::mux_file(file, &self.path)?
-            .ok_or(Error::InvalidMediaFile)
+    pub fn remove(&mut self) -> Result<()> {
+        media_mixer::remove_file(&self.path)?;
+        *self.streams.get_mut().unwrap() = None;
+        Ok(())
     }
 
     // return None if any of the hashes are not present in the file
     pub fn without_streams(
         &mut self,
         streams: Vec<String>,
+        database: &Database,
+        file_map: &FileMap,
+    ) -> Result<()>
+    {
+        let indexes: Vec<usize> = {
+            let hashes = self.stream_hashes(database)?;
+            if !streams.iter().all(|s| hashes.contains(s)) {
+                return Err(Error::StreamHashesNotFound);
+            }
+            hashes.iter()
+                .enumerate()
+                .filter(|(_, s)| streams.contains(s))
+                .map(|(i, _)| i)
+                .collect()
+        };
+        media_mixer::partial_demux_file(&indexes, &self.path)?;
+        self.store(file_map)
+    }
+
+    // Sets metadata on every stream with the given hash.
+    pub fn with_stream_metadata(
+        &mut self,
+        hash: &str,
+        metadata: &StreamMetadata,
+        database: &Database,
+        file_map: &FileMap,
     ) -> Result<()>
     {
-        let mut indexes: Vec<usize> = Vec::with_capacity(streams.len());
-        let mut streams = streams.into_iter();
-        self.stream_hashes()?
+        let indexes: Vec<usize> = self.stream_hashes(database)?
             .iter()
             .enumerate()
-            .for_each(|(i, s)| 
-                if let Some(_) = streams.find(|x| x == s) {
-                    indexes.push(i);
-                }
-            );
-        if !(indexes.len() == indexes.capacity()) {
-            return Err(Error::StreamHashesNotFound);
+            .filter(|(_, s)| *s == hash)
+            .map(|(i, _)| i)
+            .collect();
+        if indexes.is_empty() {
+            return Err(Error::StreamNotFound(hash.to_string()));
         }
-        Ok(
-            media_mixer::partial_demux_file(&indexes, &self.path)?
-        )
+        media_mixer::edit_streams(&indexes, metadata, &self.path)?;
+        self.store(file_map)
+    }
+
+    // Copies the first stream with the given hash into a standalone file.
+    pub fn extract_stream(
+        &self,
+        hash: &str,
+        database: &Database,
+    ) -> Result<ExtractedStream>
+    {
+        let index = self.stream_hashes(database)?
+            .iter()
+            .position(|s| s == hash)
+            .ok_or(Error::StreamNotFound(hash.to_string()))?;
+        media_mixer::extract_stream(index, &self.path)?
+            .ok_or(Error::StreamNotExtractable(hash.to_string()))
+    }
+
+    pub fn chapters(&self) -> Result<Vec<Chapter>> {
+        self.probe().map(|probe| probe.chapters)
+    }
+
+    // Replaces every chapter in the file. An empty list removes them.
+    pub fn with_chapters(
+        &mut self,
+        chapters: Vec<Chapter>,
+        file_map: &FileMap,
+    ) -> Result<()>
+    {
+        let valid = chapters.iter().all(|c|
+            c.start.is_finite() && c.end.is_finite()
+                && 0.0 <= c.start && c.start <= c.end
+        );
+        if !valid {
+            return Err(Error::InvalidChapters);
+        }
+        if !self.path.is_file() {
+            return Err(Error::FileNotFound);
+        }
+        media_mixer::write_chapters(&chapters, &self.path)?;
+        self.store(file_map)
     }
 
+    // Checks the in-memory hashes, then the hashes persisted in database,
+    // before falling back to hashing the file.
     pub fn stream_hashes<'a>(
         &'a self,
+        database: &Database,
     ) -> Result<impl std::ops::Deref<Target = [String]> + 'a>
     {
         let streams = self.streams.read().unwrap();
@@ -252,7 +426,7 @@ impl File {
             }
         }
         std::mem::drop(streams);
-        self.refresh_stream_hashes()?;
+        self.refresh_stream_hashes(database)?;
         Ok(Streams(self.streams.read().unwrap()))
     }
 
@@ -274,32 +448,60 @@ impl File {
             .flatten()
     }
 
-    pub fn json_probe(&self) -> Result<serde_json::Value> {
+    pub fn probe(&self) -> Result<Probe> {
         if !self.path.is_file() {
             return Err(Error::FileNotFound);
         }
         Ok(
-            media_mixer::json_probe(&self.path)?
+            media_mixer::probe(&self.path)?
         )
     }
 
+    // Drops the in-memory hashes of the file just written and hands it to
+    // file_map.
+    fn store(&mut self, file_map: &FileMap) -> Result<()> {
+        *self.streams.get_mut().unwrap() = None;
+        file_map.store(&self.id, &self.path)
+            .map_err(|e| Error::file_map_err(e))
+    }
+
     // return None if file does not exist
-    fn refresh_stream_hashes(&self) -> Result<()> {
-        *self.streams.write().unwrap() = Some((
-            media_mixer::try_hash_file(&self.path)?,
-            self.modified_time()?
-        ));
+    fn refresh_stream_hashes(&self, database: &Database) -> Result<()> {
+        let modified = self.modified_time()?;
+        let size = self.len()?.unwrap_or(0);
+        let hashes = match database.get_stream_hashes(self.id, modified, size)
+            .map_err(|e| Error::database_err(e))?
+        {
+            Some(hashes) => hashes,
+            None => {
+                let hashes = media_mixer::try_hash_file(&self.path)?;
+                database.set_stream_hashes(self.id, modified, size, &hashes)
+                    .map_err(|e| Error::database_err(e))?;
+                hashes
+            },
+        };
+        *self.streams.write().unwrap() = Some((hashes, modified));
         Ok(())
     }
 
-    fn modified_time(&self) -> Result<u64> {
+    // Seconds since the unix epoch.
+    pub fn modified_time(&self) -> Result<u64> {
+        self.modified().map(|d| d.as_secs())
+    }
+
+    // Nanoseconds since the unix epoch, for telling apart writes made within
+    // the same second.
+    pub fn modified_nanos(&self) -> Result<u128> {
+        self.modified().map(|d| d.as_nanos())
+    }
+
+    fn modified(&self) -> Result<std::time::Duration> {
         match self.path.metadata()
             .map(|m| m.modified())
         {
             Ok(Ok(m)) => Ok(m
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .map_err(|e| Error::SystemTimeError(e))?
-                .as_secs()
             ),
             Err(e) | Ok(Err(e)) if e.kind() == ErrorKind::NotFound => Err(
                 Error::FileNotFound
@@ -310,7 +512,7 @@ impl File {
 }
 
 impl<'t> ROFile<'t> {
-    pub fn into_read(self) -> Result<impl Read + 't> {
+    pub fn into_read(self) -> Result<impl Read + Seek + 't> {
         match StdFile::open(&self.path) {
             Ok(f) => Ok(Reader { file: f, rolock: self }),
             Err(e) if e.kind() == ErrorKind::NotFound => Err(
@@ -331,6 +533,16 @@ impl Read for Reader<'_> {
     }
 }
 
+impl Seek for Reader<'_> {
+    fn seek(
+        &mut self,
+        pos: SeekFrom,
+    ) -> std::result::Result<u64, std::io::Error>
+    {
+        (**self).seek(pos)
+    }
+}
+
 impl<'a> std::ops::Deref for Streams<'a> {
     type Target = [String];
     fn deref(&self) -> &Self::Target {
@@ -339,4 +551,100 @@ impl<'a> std::ops::Deref for Streams<'a> {
             None => unreachable!(), // private type, should never happen
         }
     }
-}
\ No newline at end of file
+}
+
+// An empty directory for a test, removed with its contents afterwards, even
+// if the test fails.
+#[cfg(test)]
+mod test_dir {
+    use std::{fs, path::{Path, PathBuf}};
+
+    pub struct TestDir(PathBuf);
+
+    impl TestDir {
+        pub fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "file_server_test_{}_{}",
+                std::process::id(),
+                name,
+            ));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TestDir(path)
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::core::test_dir::TestDir;
+    use std::fs;
+
+    // A FileTable entry for a path FileMap no longer returns used to be
+    // replaced while a guard on it was still held, which never returned.
+    #[test]
+    fn stale_entry() {
+        let dir = TestDir::new("stale_entry");
+        let database = Database::new(dir.path().join("db.sqlite")).unwrap();
+        let file_table = FileTable::new();
+        let file_map = FileMap::new(dir.path().join("media"), "mkv");
+        fs::create_dir_all(dir.path().join("media")).unwrap();
+        let id = FileId::new(&database, &file_table, &file_map).unwrap();
+        let mp4 = dir.path().join("media").join(format!("{}.mp4", *id));
+        fs::write(&mp4, b"not empty").unwrap();
+        assert_eq!(id.ro_file().unwrap().path, mp4);
+        fs::remove_file(&mp4).unwrap();
+        let mkv = mp4.with_extension("mkv");
+        assert_eq!(id.ro_file().unwrap().path, mkv);
+        fs::write(&mp4, b"not empty").unwrap();
+        assert_eq!(id.rw_file().unwrap().path, mp4);
+        fs::remove_file(&mp4).unwrap();
+        assert_eq!(id.rw_file().unwrap().path, mkv);
+    }
+
+    #[test]
+    fn remove_file() {
+        let dir = TestDir::new("remove_file");
+        let database = Database::new(dir.path().join("db.sqlite")).unwrap();
+        let file_table = FileTable::new();
+        let file_map = FileMap::new(dir.path().join("media"), "mkv");
+        fs::create_dir_all(dir.path().join("media")).unwrap();
+        let id = FileId::new(&database, &file_table, &file_map).unwrap();
+        let mp4 = dir.path().join("media").join(format!("{}.mp4", *id));
+        fs::write(&mp4, b"not empty").unwrap();
+        id.remove_file().unwrap();
+        assert!(!mp4.exists());
+        assert!(file_table.get(&id).is_none());
+        assert_eq!(id.ro_file().unwrap().path, mp4.with_extension("mkv"));
+    }
+
+    #[test]
+    fn remove() {
+        let dir = TestDir::new("remove");
+        let database = Database::new(dir.path().join("db.sqlite")).unwrap();
+        let file_table = FileTable::new();
+        let file_map = FileMap::new(dir.path().join("media"), "mkv");
+        fs::create_dir_all(dir.path().join("media")).unwrap();
+        let id = FileId::new(&database, &file_table, &file_map).unwrap();
+        id.with_aliases(vec!["a".to_string()]).unwrap();
+        let mkv = dir.path().join("media").join(format!("{}.mkv", *id));
+        fs::write(&mkv, b"not empty").unwrap();
+        let n = *id;
+        id.remove().unwrap();
+        assert!(!mkv.exists());
+        assert!(file_table.get(&n).is_none());
+        assert!(!database.id_exists(n).unwrap());
+        assert_eq!(database.get_id("a").unwrap(), None);
+    }
+}
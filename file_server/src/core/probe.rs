@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+
+// A normalized view of ffprobe's -show_format -show_streams -show_chapters.
+// Durations and times are in seconds.
+#[derive(Debug, Clone, Serialize)]
+pub struct Probe {
+    pub format: Format,
+    pub streams: Vec<Stream>,
+    pub chapters: Vec<Chapter>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Format {
+    pub name: String,
+    pub long_name: Option<String>,
+    pub duration: Option<f64>,
+    pub size: Option<u64>,
+    pub bit_rate: Option<u64>,
+    pub title: Option<String>,
+    pub tags: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Stream {
+    pub index: usize,
+    pub kind: StreamKind,
+    pub codec: Option<String>,
+    pub codec_long_name: Option<String>,
+    pub profile: Option<String>,
+    pub language: Option<String>,
+    pub title: Option<String>,
+    pub duration: Option<f64>,
+    pub bit_rate: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub frame_rate: Option<f64>,
+    pub channels: Option<u32>,
+    pub channel_layout: Option<String>,
+    pub sample_rate: Option<u32>,
+    pub disposition: Disposition,
+    pub tags: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamKind {
+    Video,
+    Audio,
+    Subtitle,
+    Data,
+    Attachment,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Disposition {
+    pub default: bool,
+    pub forced: bool,
+    pub original: bool,
+    pub dub: bool,
+    pub comment: bool,
+    pub hearing_impaired: bool,
+    pub visual_impaired: bool,
+    pub attached_pic: bool,
+    // Every flag as ffprobe reported it, including those not modelled above,
+    // so that none are lost when the disposition is written back.
+    #[serde(skip)]
+    reported: HashMap<String, u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chapter {
+    pub start: f64,
+    pub end: f64,
+    pub title: Option<String>,
+}
+
+// ffprobe's own json output, which reports most numbers as strings.
+mod ffprobe {
+    use std::collections::HashMap;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct Probe {
+        pub format: Option<Format>,
+        #[serde(default)]
+        pub streams: Vec<Stream>,
+        #[serde(default)]
+        pub chapters: Vec<Chapter>,
+    }
+
+    #[derive(Deserialize, Default)]
+    pub struct Format {
+        #[serde(default)]
+        pub format_name: String,
+        pub format_long_name: Option<String>,
+        pub duration: Option<String>,
+        pub size: Option<String>,
+        pub bit_rate: Option<String>,
+        #[serde(default)]
+        pub tags: HashMap<String, String>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Stream {
+        pub index: usize,
+        pub codec_type: Option<String>,
+        pub codec_name: Option<String>,
+        pub codec_long_name: Option<String>,
+        pub profile: Option<String>,
+        pub duration: Option<String>,
+        pub bit_rate: Option<String>,
+        pub width: Option<u32>,
+        pub height: Option<u32>,
+        pub avg_frame_rate: Option<String>,
+        pub channels: Option<u32>,
+        pub channel_layout: Option<String>,
+        pub sample_rate: Option<String>,
+        #[serde(default)]
+        pub disposition: HashMap<String, u8>,
+        #[serde(default)]
+        pub tags: HashMap<String, String>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Chapter {
+        pub start_time: String,
+        pub end_time: String,
+        #[serde(default)]
+        pub tags: HashMap<String, String>,
+    }
+}
+
+impl Probe {
+    pub(super) fn from_ffprobe(bytes: &[u8]) -> serde_json::Result<Self> {
+        let probe: ffprobe::Probe = serde_json::from_slice(bytes)?;
+        Ok(Self {
+            format: probe.format.unwrap_or_default().into(),
+            streams: probe.streams.into_iter().map(Stream::from).collect(),
+            chapters: probe.chapters.into_iter().map(Chapter::from).collect(),
+        })
+    }
+}
+
+impl From<ffprobe::Format> for Format {
+    fn from(value: ffprobe::Format) -> Self {
+        Self {
+            name: value.format_name,
+            long_name: value.format_long_name,
+            duration: parse(&value.duration),
+            size: parse(&value.size),
+            bit_rate: parse(&value.bit_rate),
+            title: tag(&value.tags, "title"),
+            tags: value.tags,
+        }
+    }
+}
+
+impl From<ffprobe::Stream> for Stream {
+    fn from(value: ffprobe::Stream) -> Self {
+        let flag = |key: &str| value.disposition.get(key)
+            .map_or(false, |v| *v != 0);
+        let disposition = Disposition {
+            default: flag("default"),
+            forced: flag("forced"),
+            original: flag("original"),
+            dub: flag("dub"),
+            comment: flag("comment"),
+            hearing_impaired: flag("hearing_impaired"),
+            visual_impaired: flag("visual_impaired"),
+            attached_pic: flag("attached_pic"),
+            reported: value.disposition.clone(),
+        };
+        // Bound first, since the fields below are moved out of value.
+        let duration_tag = tag(&value.tags, "duration");
+        Self {
+            index: value.index,
+            kind: match value.codec_type.as_ref().map(|s| s.as_str()) {
+                Some("video") => StreamKind::Video,
+                Some("audio") => StreamKind::Audio,
+                Some("subtitle") => StreamKind::Subtitle,
+                Some("data") => StreamKind::Data,
+                Some("attachment") => StreamKind::Attachment,
+                _ => StreamKind::Unknown,
+            },
+            codec: value.codec_name,
+            codec_long_name: value.codec_long_name,
+            profile: value.profile,
+            // "und" is ffmpeg's way of saying that there is no language.
+            language: tag(&value.tags, "language")
+                .filter(|l| l != "und"),
+            title: tag(&value.tags, "title"),
+            duration: parse(&value.duration)
+                .or_else(|| duration_tag
+                    .and_then(|d| parse_timestamp(&d))
+                ),
+            bit_rate: parse(&value.bit_rate),
+            width: value.width,
+            height: value.height,
+            frame_rate: value.avg_frame_rate.as_ref()
+                .and_then(|r| parse_ratio(r)),
+            channels: value.channels,
+            channel_layout: value.channel_layout,
+            sample_rate: parse(&value.sample_rate),
+            disposition: disposition,
+            tags: value.tags,
+        }
+    }
+}
+
+impl From<ffprobe::Chapter> for Chapter {
+    fn from(value: ffprobe::Chapter) -> Self {
+        Self {
+            start: value.start_time.parse().unwrap_or(0.0),
+            end: value.end_time.parse().unwrap_or(0.0),
+            title: tag(&value.tags, "title"),
+        }
+    }
+}
+
+impl Disposition {
+    // The value of ffmpeg's -disposition option, which replaces every flag.
+    // Only default and forced can be changed, so every other flag is kept as
+    // ffprobe reported it.
+    pub(super) fn to_ffmpeg(&self) -> String {
+        let mut flags: Vec<&str> = self.reported.iter()
+            .filter(|(name, set)| **set != 0
+                && name.as_str() != "default"
+                && name.as_str() != "forced"
+            )
+            .map(|(name, _)| name.as_str())
+            .chain(Some("default").filter(|_| self.default))
+            .chain(Some("forced").filter(|_| self.forced))
+            .collect();
+        flags.sort();
+        match flags.is_empty() {
+            true => "0".to_string(),
+            false => flags.join("+"),
+        }
+    }
+}
+
+fn parse<T: std::str::FromStr>(value: &Option<String>) -> Option<T> {
+    value.as_ref().and_then(|v| v.parse().ok())
+}
+
+// Container tags differ in case between formats, e.g. "title" and "TITLE".
+fn tag(tags: &HashMap<String, String>, key: &str) -> Option<String> {
+    tags.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(key))
+        .map(|(_, v)| v.clone())
+}
+
+// "24000/1001", where "0/0" means unknown.
+fn parse_ratio(value: &str) -> Option<f64> {
+    let mut parts = value.splitn(2, '/');
+    let num: f64 = parts.next()?.parse().ok()?;
+    let den: f64 = parts.next()?.parse().ok()?;
+    match den == 0.0 {
+        true => None,
+        false => Some(num / den),
+    }
+}
+
+// "01:23:45.678000000", as written by the Matroska muxer.
+fn parse_timestamp(value: &str) -> Option<f64> {
+    value.split(':').try_fold(0.0, |acc, part| part.parse::<f64>()
+        .ok()
+        .map(|p| acc * 60.0 + p)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Trimmed from ffprobe -show_format -show_streams -show_chapters run on
+    // a Matroska file, which keeps stream durations in tags.
+    const FIXTURE: &str = r#"{
+        "streams": [
+            {
+                "index": 0,
+                "codec_name": "h264",
+                "codec_long_name": "H.264 / AVC / MPEG-4 AVC / MPEG-4 part 10",
+                "profile": "High",
+                "codec_type": "video",
+                "width": 1920,
+                "height": 1080,
+                "avg_frame_rate": "24000/1001",
+                "disposition": {"default": 1, "forced": 0},
+                "tags": {"DURATION": "00:01:30.500000000"}
+            },
+            {
+                "index": 1,
+                "codec_name": "opus",
+                "codec_type": "audio",
+                "channels": 2,
+                "channel_layout": "stereo",
+                "sample_rate": "48000",
+                "duration": "90.250000",
+                "disposition": {"default": 0},
+                "tags": {"language": "und", "title": "Commentary"}
+            },
+            {
+                "index": 2,
+                "codec_type": "subtitle",
+                "avg_frame_rate": "0/0",
+                "tags": {"LANGUAGE": "eng"}
+            }
+        ],
+        "chapters": [
+            {"start_time": "0.000000", "end_time": "45.000000", "tags": {"title": "One"}},
+            {"start_time": "45.000000", "end_time": "90.500000"}
+        ],
+        "format": {
+            "format_name": "matroska,webm",
+            "format_long_name": "Matroska / WebM",
+            "duration": "90.500000",
+            "size": "1048576",
+            "bit_rate": "92691",
+            "tags": {"TITLE": "Example"}
+        }
+    }"#;
+
+    #[test]
+    fn from_ffprobe() {
+        let probe = Probe::from_ffprobe(FIXTURE.as_bytes()).unwrap();
+        assert_eq!(probe.format.name, "matroska,webm");
+        assert_eq!(probe.format.duration, Some(90.5));
+        assert_eq!(probe.format.size, Some(1048576));
+        assert_eq!(probe.format.title.as_deref(), Some("Example"));
+
+        let video = &probe.streams[0];
+        assert_eq!(video.kind, StreamKind::Video);
+        assert_eq!(video.codec.as_deref(), Some("h264"));
+        assert_eq!(video.duration, Some(90.5));
+        assert!((video.frame_rate.unwrap() - 23.976).abs() < 0.001);
+        assert!(video.disposition.default);
+        assert!(!video.disposition.forced);
+
+        let audio = &probe.streams[1];
+        assert_eq!(audio.kind, StreamKind::Audio);
+        assert_eq!(audio.language, None);
+        assert_eq!(audio.title.as_deref(), Some("Commentary"));
+        assert_eq!(audio.duration, Some(90.25));
+        assert_eq!(audio.sample_rate, Some(48000));
+
+        let subtitle = &probe.streams[2];
+        assert_eq!(subtitle.kind, StreamKind::Subtitle);
+        assert_eq!(subtitle.language.as_deref(), Some("eng"));
+        assert_eq!(subtitle.frame_rate, None);
+
+        assert_eq!(probe.chapters.len(), 2);
+        assert_eq!(probe.chapters[0].title.as_deref(), Some("One"));
+        assert_eq!(probe.chapters[1].end, 90.5);
+        assert_eq!(probe.chapters[1].title, None);
+    }
+
+    #[test]
+    fn disposition_keeps_other_flags() {
+        let probe = Probe::from_ffprobe(br#"{"streams": [{
+            "index": 0,
+            "codec_type": "subtitle",
+            "disposition": {
+                "default": 0, "forced": 1, "captions": 1, "lyrics": 0,
+                "hearing_impaired": 1
+            }
+        }]}"#).unwrap();
+        let mut disposition = probe.streams[0].disposition.clone();
+        assert_eq!(
+            disposition.to_ffmpeg(),
+            "captions+forced+hearing_impaired",
+        );
+        disposition.default = true;
+        disposition.forced = false;
+        assert_eq!(
+            disposition.to_ffmpeg(),
+            "captions+default+hearing_impaired",
+        );
+        assert_eq!(Disposition::default().to_ffmpeg(), "0");
+    }
+
+    #[test]
+    fn missing_format() {
+        let probe = Probe::from_ffprobe(br#"{"streams": []}"#).unwrap();
+        assert_eq!(probe.format.name, "");
+        assert!(probe.streams.is_empty());
+        assert!(probe.chapters.is_empty());
+    }
+
+    #[test]
+    fn timestamps_and_ratios() {
+        assert_eq!(parse_timestamp("01:23:45.5"), Some(5025.5));
+        assert_eq!(parse_timestamp("1:x"), None);
+        assert_eq!(parse_ratio("30/1"), Some(30.0));
+        assert_eq!(parse_ratio("0/0"), None);
+        assert_eq!(parse_ratio("30"), None);
+    }
+}
@@ -0,0 +1,143 @@
+use std::{path::{Path, Component}, sync::mpsc, thread, time::Duration};
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use super::{Database, FileMap, FileTable, Error, Result, DB};
+
+// Events for a path are coalesced over this long, so that a file being
+// written is only handled once it settles.
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+// Watches the media root from a new thread, dropping the in-memory and the
+// persisted stream hashes of every file that changes, appears or disappears,
+// since mtime alone misses changes within the same second. Files whose id is
+// not in database are reported as orphaned, first for every file already
+// there, then as they appear.
+//
+// Hidden files and directories, such as media_mixer's temporary files, are
+// ignored; renaming one over a file is seen as a change to that file.
+pub fn watch(
+    database: &'static Database,
+    file_table: &'static FileTable,
+    file_map: &'static FileMap,
+) -> Result<()>
+{
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::watcher(tx, DEBOUNCE)
+        .map_err(|e| Error::file_map_err(e))?;
+    watcher.watch(file_map.base_path(), RecursiveMode::Recursive)
+        .map_err(|e| Error::file_map_err(e))?;
+    thread::spawn(move || {
+        // Dropping the watcher would stop it.
+        let _watcher = watcher;
+        let watch = Watch {
+            database: database,
+            file_table: file_table,
+            file_map: file_map,
+        };
+        watch.report_orphans();
+        for event in rx {
+            watch.handle(event);
+        }
+    });
+    Ok(())
+}
+
+struct Watch {
+    database: &'static Database,
+    file_table: &'static FileTable,
+    file_map: &'static FileMap,
+}
+
+impl Watch {
+    fn handle(&self, event: DebouncedEvent) {
+        match event {
+            DebouncedEvent::Create(path) => {
+                self.changed(&path);
+                self.check_orphan(&path);
+            },
+            DebouncedEvent::Write(path)
+                | DebouncedEvent::Chmod(path)
+                | DebouncedEvent::Remove(path) => self.changed(&path),
+            DebouncedEvent::Rename(from, to) => {
+                self.changed(&from);
+                self.changed(&to);
+                self.check_orphan(&to);
+            },
+            // Events were dropped, so anything may have changed.
+            DebouncedEvent::Rescan => self.changed_all(),
+            DebouncedEvent::Error(e, path) => eprintln!(
+                "watcher error{}: {}",
+                path.map(|p| format!(" for {}", p.display()))
+                    .unwrap_or_default(),
+                e,
+            ),
+            DebouncedEvent::NoticeWrite(_)
+                | DebouncedEvent::NoticeRemove(_) => (),
+        };
+    }
+
+    fn changed(&self, path: &Path) {
+        if let Some(id) = self.file_id(path) {
+            self.invalidate(id);
+        }
+    }
+
+    fn changed_all(&self) {
+        match self.database.list_ids() {
+            Ok(ids) => ids.into_iter().for_each(|id| self.invalidate(id)),
+            Err(e) => eprintln!("failed to list ids: {}", e),
+        };
+    }
+
+    // Only the stream hashes need dropping, since FileId replaces a FileTable
+    // entry whose path FileMap no longer returns. A read lock on the entry
+    // is enough, so a long download does not hold this up.
+    fn invalidate(&self, id: u32) {
+        if let Some(file) = self.file_table.get(&id) {
+            *file.streams.write().unwrap() = None;
+        }
+        if let Err(e) = self.database.remove_stream_hashes(id) {
+            eprintln!("failed to remove stream hashes of {}: {}", id, e);
+        }
+    }
+
+    fn check_orphan(&self, path: &Path) {
+        let id = match self.file_id(path) {
+            Some(id) if path.is_file() => id,
+            _ => return,
+        };
+        match self.database.id_exists(id) {
+            Ok(true) => (),
+            Ok(false) => eprintln!(
+                "orphaned file {}: id {} does not exist",
+                path.display(),
+                id,
+            ),
+            Err(e) => eprintln!("failed to look up id {}: {}", id, e),
+        };
+    }
+
+    fn report_orphans(&self) {
+        let files = match self.file_map.files() {
+            Ok(files) => files,
+            Err(e) => return eprintln!("failed to list media root: {}", e),
+        };
+        for (_, path) in files {
+            self.check_orphan(&path);
+        }
+    }
+
+    // The id a path under the media root would be stored for, going by its
+    // stem, or None if it is hidden or not named after an id.
+    fn file_id(&self, path: &Path) -> Option<u32> {
+        let relative = path.strip_prefix(self.file_map.base_path()).ok()?;
+        let hidden = relative.components().any(|c| match c {
+            Component::Normal(name) => name.to_str()
+                .map_or(true, |s| s.starts_with('.')),
+            _ => false,
+        });
+        if hidden {
+            return None;
+        }
+        relative.file_stem()?.to_str()?.parse().ok()
+    }
+}
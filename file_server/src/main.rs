@@ -1,3 +1,161 @@
+use std::{env, path::PathBuf, process};
+use file_server::microservice::{self, Database, FileMap, FileTable, Layout, UploadLimits};
+
+const USAGE: &str = "usage: file_server <database> <media_root> [extension]
+
+Arguments may also be provided through the FILE_SERVER_DATABASE,
+FILE_SERVER_MEDIA_ROOT and FILE_SERVER_EXTENSION environment variables.
+FILE_SERVER_LAYOUT selects how files are arranged under the media root,
+either \"flat\" (the default) or \"sharded\". Starting with either layout
+moves any files left in the other one into place.
+FILE_SERVER_WATCH, either \"on\" (the default) or \"off\", sets whether the
+media root is watched for changes made outside of the server, which also
+reports files whose id does not exist.
+FILE_SERVER_UPLOAD_LIMIT is the most bytes a single upload may have, with no
+limit by default. FILE_SERVER_MIN_FREE is the number of bytes uploads must
+leave free on the filesystem of the media root, 1073741824 by default.
+When built with the postgres feature, <database> is a connection string such
+as \"host=localhost user=postgres dbname=file_server\" instead of a path.
+When built with the dedup feature, files already in the media root are
+deduplicated and unused blobs are removed at startup.
+The listening address and port are configured through Rocket.toml or the
+ROCKET_ADDRESS and ROCKET_PORT environment variables.";
+
+const DEFAULT_EXTENSION: &str = "mkv";
+
+const DEFAULT_MIN_FREE: u64 = 1 << 30;
+
+struct Config {
+    // A path, or a connection string for Postgres.
+    database: String,
+    media_root: PathBuf,
+    extension: String,
+    layout: Layout,
+    watch: bool,
+    limits: UploadLimits,
+}
+
+impl Config {
+    // Positional arguments take precedence over environment variables.
+    fn from_env() -> Result<Self, String> {
+        let mut args = env::args().skip(1);
+        let mut arg_or_var = |var: &str| args.next()
+            .or_else(|| env::var(var).ok());
+        let database = arg_or_var("FILE_SERVER_DATABASE")
+            .ok_or("missing database path")?;
+        let media_root = arg_or_var("FILE_SERVER_MEDIA_ROOT")
+            .ok_or("missing media root")?;
+        let extension = arg_or_var("FILE_SERVER_EXTENSION")
+            .unwrap_or(DEFAULT_EXTENSION.to_string());
+        let layout = match env::var("FILE_SERVER_LAYOUT").as_deref() {
+            Ok("flat") | Err(_) => Layout::Flat,
+            Ok("sharded") => Layout::Sharded,
+            Ok(other) => return Err(format!("unknown layout {:?}", other)),
+        };
+        let watch = match env::var("FILE_SERVER_WATCH").as_deref() {
+            Ok("on") | Err(_) => true,
+            Ok("off") => false,
+            Ok(other) => return Err(format!("unknown watch mode {:?}", other)),
+        };
+        let bytes = |var: &str| match env::var(var) {
+            Ok(s) => s.parse::<u64>()
+                .map(Some)
+                .map_err(|e| format!("invalid {}: {}", var, e)),
+            Err(_) => Ok(None),
+        };
+        let limits = UploadLimits {
+            max_bytes: bytes("FILE_SERVER_UPLOAD_LIMIT")?,
+            min_free: bytes("FILE_SERVER_MIN_FREE")?
+                .unwrap_or(DEFAULT_MIN_FREE),
+        };
+        Ok(Self {
+            database: database,
+            media_root: media_root.into(),
+            extension: extension.trim_start_matches('.').to_string(),
+            layout: layout,
+            watch: watch,
+            limits: limits,
+        })
+    }
+}
+
+fn exit_with(message: impl std::fmt::Display, code: i32) -> ! {
+    eprintln!("{}", message);
+    process::exit(code)
+}
+
 fn main() {
-    println!("Hello World!")
-}
\ No newline at end of file
+    let config = Config::from_env()
+        .unwrap_or_else(|e| exit_with(format!("{}\n\n{}", e, USAGE), 2));
+    if !config.media_root.is_dir() {
+        exit_with(format!(
+            "media root {} is not a directory",
+            config.media_root.display(),
+        ), 1);
+    }
+    let database = Database::new(&config.database)
+        .unwrap_or_else(|e| exit_with(format!(
+            "failed to open database {}: {}",
+            config.database,
+            e,
+        ), 1));
+    let file_map = FileMap::with_layout(
+        &config.media_root,
+        Box::leak(config.extension.into_boxed_str()),
+        config.layout,
+    );
+    // Files left in the other layout, such as after the layout is changed,
+    // are moved into the configured one.
+    let other_layout = match config.layout {
+        Layout::Flat => Layout::Sharded,
+        Layout::Sharded => Layout::Flat,
+    };
+    match file_map.migrate(other_layout) {
+        Ok(0) => (),
+        Ok(n) => eprintln!(
+            "moved {} files into the {:?} layout",
+            n,
+            config.layout,
+        ),
+        Err(e) => exit_with(
+            format!("failed to migrate media root: {}", e),
+            1,
+        ),
+    };
+    #[cfg(feature = "dedup")]
+    {
+        let deduplicated = file_map.deduplicate()
+            .and_then(|stored| Ok((stored, file_map.collect_garbage()?)));
+        match deduplicated {
+            Ok((0, 0)) => (),
+            Ok((stored, removed)) => eprintln!(
+                "deduplicated {} files and removed {} unused blobs",
+                stored,
+                removed,
+            ),
+            Err(e) => exit_with(
+                format!("failed to deduplicate media root: {}", e),
+                1,
+            ),
+        };
+    }
+    // The server runs for the lifetime of the process, so its states can be
+    // leaked into 'static references.
+    let database: &'static Database = Box::leak(Box::new(database));
+    let file_table: &'static FileTable = Box::leak(Box::new(FileTable::new()));
+    let file_map: &'static FileMap = Box::leak(Box::new(file_map));
+    if config.watch {
+        microservice::watch(database, file_table, file_map)
+            .unwrap_or_else(|e| exit_with(
+                format!("failed to watch media root: {}", e),
+                1,
+            ));
+    }
+    let error = microservice::run(
+        database,
+        file_table,
+        file_map,
+        config.limits,
+    );
+    exit_with(format!("failed to launch server: {}", error), 1)
+}
@@ -1,20 +1,28 @@
-use rocket::{State, http::RawStr, data::Data, request::FromParam};
-use crate::core::{self, FileMap, FileTable, Database, FileId};
+use rocket::{State, Request, Outcome, http::RawStr, data::Data, request::{self, FromParam, FromRequest}, error::LaunchError};
+use crate::core::{self, FileMap, FileTable, Database, FileId, StreamMetadata, Chapter, UploadLimits};
 use std::{iter, result::Result as StdResult};
 use super::{FileContent, Content, Error};
 use rocket_contrib::json::Json;
 
 type Result<T> = std::result::Result<T, Error>;
 
+const DEFAULT_SEARCH_LIMIT: u32 = 1000;
+const DEFAULT_PAGE_LIMIT: u32 = 1000;
+const MAX_PAGE_LIMIT: u32 = 10000;
+
 enum Id {
     Alias(String),
     Id(u32),
 }
 
+// Every "alias" value in the query string, in order.
+struct Aliases(Vec<String>);
+
 struct States<'db, 't, 'm> {
     database: &'db Database,
     file_table: &'t FileTable,
     file_map: &'m FileMap,
+    limits: UploadLimits,
 }
 
 impl Id {
@@ -38,6 +46,13 @@ impl Id {
             )?,
         })
     }
+    // Keys in request bodies are read the same way as path parameters.
+    fn from_key(key: &str) -> Self {
+        match key.parse::<u32>() {
+            Ok(i) => Self::Id(i),
+            Err(_) => Self::Alias(key.to_string()),
+        }
+    }
     fn into_key(self) -> String {
         match self {
             Self::Alias(s) => s,
@@ -56,34 +71,143 @@ impl<'r> FromParam<'r> for Id {
     }
 }
 
+impl<'a, 'r> FromRequest<'a, 'r> for Aliases {
+    type Error = ();
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let aliases = request.uri().query()
+            .map(|query| query
+                .split('&')
+                .filter_map(|pair| {
+                    let mut pair = pair.splitn(2, '=');
+                    match (pair.next(), pair.next()) {
+                        (Some("alias"), Some(v)) => RawStr::from_str(v)
+                            .url_decode()
+                            .ok(),
+                        _ => None,
+                    }
+                })
+                .collect()
+            )
+            .unwrap_or_default();
+        Outcome::Success(Aliases(aliases))
+    }
+}
+
+// Only returns if the server fails to launch.
 pub fn run<'db: 'static, 't: 'static, 'm: 'static>(
     database: &'db Database,
     file_table: &'t FileTable,
     file_map: &'m FileMap,
-) {
+    limits: UploadLimits,
+) -> LaunchError
+{
     rocket::ignite()
         .manage(States {
             database: database,
             file_table: file_table,
             file_map: file_map,
-        });
+            limits: limits,
+        })
+        .mount("/", routes![
+            list,
+            create_file,
+            delete_file,
+            get_file,
+            push_file,
+            pop_file,
+            get_stream_hashes,
+            batch_stream_hashes,
+            get_stream,
+            patch_stream,
+            get_probe,
+            batch_probe,
+            get_chapters,
+            put_chapters,
+            delete_chapters,
+            get_aliases,
+            search_aliases,
+            push_aliases,
+            pop_aliases,
+            get_fsck,
+            repair_fsck,
+        ])
+        .launch()
 }
 
-#[get("/list")]
-fn list(states: State<States>) -> Result<Content> {
-    Ok(Content::alias_list(FileId::all(
+// Without any parameters, every id is returned as {id: [aliases]}, as before
+// paging was added. Otherwise a page is returned as {items, next}, where next
+// is the after of the following page.
+#[get("/list?<after>&<limit>&<has_alias>&<namespace>")]
+fn list(
+    after: Option<u32>,
+    limit: Option<u32>,
+    has_alias: Option<bool>,
+    namespace: Option<String>,
+    states: State<States>,
+) -> Result<Content>
+{
+    if let (None, None, None, None) = (after, limit, has_alias, &namespace) {
+        let mut ids: Vec<(u32, Vec<String>)> = Vec::new();
+        loop {
+            let page = core::list_page(
+                states.database,
+                ids.last().map(|(id, _)| *id),
+                MAX_PAGE_LIMIT,
+                None,
+                None,
+            )?;
+            let last = page.len() < MAX_PAGE_LIMIT as usize;
+            ids.extend(page);
+            if last {
+                return Ok(Content::alias_list(ids.into_iter()));
+            }
+        }
+    }
+    let limit = limit.unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT);
+    Ok(core::list_page(
+        states.database,
+        after,
+        limit,
+        has_alias,
+        namespace.as_ref().map(|s| s.as_str()),
+    )
+        .map(|page| Content::alias_page(page, limit))?
+    )
+}
+
+// Creates a new id with any aliases given as ?alias=... and, if the body is
+// not empty, the uploaded file. Nothing is kept if any step fails.
+#[post("/files", data = "<data>")]
+fn create_file(
+    aliases: Aliases,
+    states: State<States>,
+    data: Data,
+) -> Result<Content>
+{
+    let has_upload = !(data.peek().is_empty() && data.peek_complete());
+    let id = FileId::new(
         states.database,
         states.file_table,
         states.file_map,
-    )?
-        .into_iter()
-        .map(|id| Ok((
-            *id,
-            id.get_aliases()?
-        )))
-        .collect::<StdResult<Vec<(u32, Vec<String>)>, core::Error>>()?
-        .into_iter()
-    ))
+    )?;
+    let created = id.with_aliases(aliases.0.clone())
+        .and_then(|_| match has_upload {
+            true => id.with_file(data.open(), &states.limits),
+            false => Ok(()),
+        });
+    if let Err(e) = created {
+        let _ = id.remove();
+        return Err(e.into());
+    }
+    Ok(Content::created(*id, aliases.0))
+}
+
+#[delete("/files/<id>")]
+fn delete_file(id: Id, states: State<States>) -> Result<Content> {
+    Ok(id.as_file_id(&states)?
+        .remove()
+        .map(|_| Content::okay())?
+    )
 }
 
 #[get("/files/<id>")]
@@ -102,13 +226,14 @@ fn get_file<'t>(
 #[post("/files/<id>?push", data = "<data>")]
 fn push_file(id: Id, states: State<States>, data: Data) -> Result<Content> {
     Ok(id.as_file_id(&states)?
-        .rw_file()?
-        .with_file(data.open())
+        .with_file(data.open(), &states.limits)
         .map(|_| Content::okay())?
     )
 }
 
-#[post("/files/<id>?pop", format = "json", data = "<list>")]
+// Rocket ignores the query when checking for collisions, so the ?pop routes
+// are ranked after the ?push routes and are reached when those forward.
+#[post("/files/<id>?pop", format = "json", data = "<list>", rank = 2)]
 fn pop_file(
     id: Id,
     states: State<States>,
@@ -117,7 +242,11 @@ fn pop_file(
 {
     Ok(id.as_file_id(&states)?
         .rw_file()?
-        .without_streams(list.into_inner())
+        .without_streams(
+            list.into_inner(),
+            states.database,
+            states.file_map,
+        )
         .map(|_| Content::okay())?
     )
 }
@@ -126,19 +255,118 @@ fn pop_file(
 fn get_stream_hashes(id: Id, states: State<States>) -> Result<Content> {
     Ok(id.as_file_id(&states)?
         .ro_file()?
-        .stream_hashes()
+        .stream_hashes(states.database)
         .map(|hashes| Content::stream_hashes(
             iter::once((id.into_key(), &*hashes))
         ))?
     )
 }
 
+#[post("/streams", format = "json", data = "<list>")]
+fn batch_stream_hashes(
+    list: Json<Vec<String>>,
+    states: State<States>,
+) -> Content
+{
+    Content::batch(list.into_inner()
+        .into_iter()
+        .map(|key| {
+            let hashes = Id::from_key(&key)
+                .as_file_id(&states)
+                .and_then(|id| Ok(id
+                    .ro_file()?
+                    .stream_hashes(states.database)?
+                    .to_vec()
+                ));
+            (key, hashes)
+        })
+    )
+}
+
+#[get("/streams/<id>/<hash>")]
+fn get_stream(
+    id: Id,
+    hash: String,
+    states: State<States>,
+) -> Result<FileContent<'static>>
+{
+    let stream = id.as_file_id(&states)?
+        .ro_file()?
+        .extract_stream(&hash, states.database)?;
+    Ok(FileContent::stream(&hash, stream)?)
+}
+
+#[patch("/streams/<id>/<hash>", format = "json", data = "<metadata>")]
+fn patch_stream(
+    id: Id,
+    hash: String,
+    states: State<States>,
+    metadata: Json<StreamMetadata>,
+) -> Result<Content>
+{
+    Ok(id.as_file_id(&states)?
+        .rw_file()?
+        .with_stream_metadata(
+            &hash,
+            &metadata,
+            states.database,
+            states.file_map,
+        )
+        .map(|_| Content::okay())?
+    )
+}
+
 #[get("/probe/<id>")]
 fn get_probe(id: Id, states: State<States>) -> Result<Content> {
+    Ok(id.as_file_id(&states)?
+        .ro_file()?
+        .probe()
+        .map(|probe| Content::probe(&probe))?
+    )
+}
+
+#[post("/probe", format = "json", data = "<list>")]
+fn batch_probe(list: Json<Vec<String>>, states: State<States>) -> Content {
+    Content::batch(list.into_inner()
+        .into_iter()
+        .map(|key| {
+            let probe = Id::from_key(&key)
+                .as_file_id(&states)
+                .and_then(|id| Ok(id.ro_file()?.probe()?));
+            (key, probe)
+        })
+    )
+}
+
+#[get("/chapters/<id>")]
+fn get_chapters(id: Id, states: State<States>) -> Result<Content> {
+    Ok(id.as_file_id(&states)?
+        .ro_file()?
+        .chapters()
+        .map(|chapters| Content::chapters(&chapters))?
+    )
+}
+
+#[put("/chapters/<id>", format = "json", data = "<list>")]
+fn put_chapters(
+    id: Id,
+    list: Json<Vec<Chapter>>,
+    states: State<States>,
+) -> Result<Content>
+{
     Ok(id.as_file_id(&states)?
         .rw_file()?
-        .json_probe()
-        .map(|json| Content::json_probe(json))?
+        .with_chapters(list.into_inner(), states.file_map)
+        .map(|_| Content::okay())?
+    )
+}
+
+#[delete("/chapters/<id>")]
+fn delete_chapters(id: Id, states: State<States>) -> Result<Content> {
+    Ok(id.as_file_id(&states)?
+        .rw_file()?
+        .with_chapters(Vec::new(), states.file_map)
+        .map(|_| Content::okay())?
     )
 }
 
@@ -152,6 +380,24 @@ fn get_aliases(id: Id, states: State<States>) -> Result<Content> {
     )
 }
 
+#[get("/aliases?<namespace>&<prefix>&<limit>")]
+fn search_aliases(
+    namespace: Option<String>,
+    prefix: Option<String>,
+    limit: Option<u32>,
+    states: State<States>,
+) -> Result<Content>
+{
+    Ok(core::search_aliases(
+        states.database,
+        namespace.as_ref().map(|s| s.as_str()),
+        prefix.as_ref().map_or("", |s| s.as_str()),
+        limit.unwrap_or(DEFAULT_SEARCH_LIMIT).min(MAX_PAGE_LIMIT),
+    )
+        .map(|aliases| Content::alias_search(aliases.into_iter()))?
+    )
+}
+
 #[post("/aliases/<id>?push", format = "json", data = "<list>")]
 fn push_aliases(
     id: Id,
@@ -165,7 +411,7 @@ fn push_aliases(
     )
 }
 
-#[post("/aliases/<id>?pop", format = "json", data = "<list>")]
+#[post("/aliases/<id>?pop", format = "json", data = "<list>", rank = 2)]
 fn pop_aliases(
     id: Id,
     list: Json<Vec<String>>,
@@ -176,4 +422,30 @@ fn pop_aliases(
         .without_aliases(list.into_inner())
         .map(|_| Content::okay())?
     )
-}
\ No newline at end of file
+}
+
+// Reports inconsistencies between the database and the media root.
+#[get("/fsck")]
+fn get_fsck(states: State<States>) -> Result<Content> {
+    Ok(core::fsck(
+        states.database,
+        states.file_table,
+        states.file_map,
+        false,
+    )
+        .map(|report| Content::fsck(&report))?
+    )
+}
+
+// Reports inconsistencies as GET /fsck does, after fixing them.
+#[post("/fsck")]
+fn repair_fsck(states: State<States>) -> Result<Content> {
+    Ok(core::fsck(
+        states.database,
+        states.file_table,
+        states.file_map,
+        true,
+    )
+        .map(|report| Content::fsck(&report))?
+    )
+}
@@ -2,4 +2,5 @@ mod responder;
 use responder::{Content, Error, FileContent};
 
 mod microservice;
-pub use microservice::run;
\ No newline at end of file
+pub use microservice::run;
+pub use crate::core::{Database, FileMap, FileTable, Layout, UploadLimits, watch};
\ No newline at end of file
@@ -1,5 +1,5 @@
-use std::{error::Error as StdError, convert::TryFrom, collections::HashMap, io::BufReader, hash::Hash};
-use rocket::{response::{Response, Responder, Body}, http::ContentType};
+use std::{error::Error as StdError, convert::TryFrom, collections::HashMap, io::{BufReader, Read, Seek, SeekFrom}, hash::Hash};
+use rocket::{response::{Response, Responder, Body}, http::{ContentType, Status}};
 use serde_json::Value as Json;
 use lazy_static::lazy_static;
 use serde::Serialize;
@@ -16,38 +16,101 @@ struct Field<T, E> {
 pub enum Content {
     #[response(status = 200, content_type = "json")]
     Okay(&'static str),
+    #[response(status = 201, content_type = "json")]
+    Created(String),
     #[response(status = 200, content_type = "json")]
     StreamHashes(String),
     #[response(status = 200, content_type = "json")]
     AliasList(String),
     #[response(status = 200, content_type = "json")]
-    JsonProbe(String),
+    AliasSearch(String),
+    #[response(status = 200, content_type = "json")]
+    AliasPage(String),
+    #[response(status = 200, content_type = "json")]
+    Probe(String),
+    #[response(status = 200, content_type = "json")]
+    Chapters(String),
+    #[response(status = 200, content_type = "json")]
+    Batch(String),
+    #[response(status = 200, content_type = "json")]
+    Fsck(String),
 }
 
 pub struct FileContent<'r> {
     len: Option<u64>,
     ext: Option<String>,
-    reader: Box<dyn std::io::Read + 'r>,
+    etag: String,
+    // Whether Range requests are served, which needs the same ETag to
+    // always mean the same bytes.
+    ranges: bool,
+    reader: Box<dyn ReadSeek + 'r>,
+}
+
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+#[derive(Debug, PartialEq)]
+enum Range {
+    Satisfiable(u64, u64),
+    Unsatisfiable,
 }
 
+// Every error body has the shape
+//     {"content": null, "status": false, "error": {"kind", "text", "content"}}
+// where "kind" is one of the following machine-readable codes:
+//
+//     kind                    status  error.content
+//     Internal                500     {display, debug, source}
+//     FileNotFound            404     null
+//     AliasNotFound           404     {alias}
+//     IDNotFound              404     {id}
+//     StreamHashesNotFound    400     null
+//     StreamNotFound          404     {hash}
+//     StreamNotExtractable    422     {hash}
+//     InvalidChapters         400     null
+//     InvalidMediaFile        415     null
+//     AliasesAlreadyExist     409     null
+//     AliasDoesNotMatchID     409     {id, alias}
+//     MediaToolUnavailable    503     {display, debug, source}
+//     MediaToolFailed         500     {display, debug, source}
+//     InvalidMediaToolOutput  502     {display, debug, source}
+//     UploadTooLarge          413     {limit}
+//     InsufficientStorage     507     null
 #[derive(Debug, Responder)]
 pub enum Error {
     #[response(status = 500, content_type = "json")]
     InternalError(String),
-    #[response(status = 400, content_type = "json")]
+    #[response(status = 404, content_type = "json")]
     FileNotFound(&'static str),
-    #[response(status = 400, content_type = "json")]
+    #[response(status = 404, content_type = "json")]
     AliasNotFound(String),
-    #[response(status = 400, content_type = "json")]
+    #[response(status = 404, content_type = "json")]
     IdNotFound(String),
     #[response(status = 400, content_type = "json")]
     StreamHashesNotFound(&'static str),
+    #[response(status = 404, content_type = "json")]
+    StreamNotFound(String),
+    #[response(status = 422, content_type = "json")]
+    StreamNotExtractable(String),
     #[response(status = 400, content_type = "json")]
+    InvalidChapters(&'static str),
+    #[response(status = 415, content_type = "json")]
     InvalidMediaFile(&'static str),
-    #[response(status = 400, content_type = "json")]
+    #[response(status = 409, content_type = "json")]
     AliasesAlreadyExist(&'static str),
-    #[response(status = 400, content_type = "json")]
+    #[response(status = 409, content_type = "json")]
     AliasDoesNotMatchId(String),
+    #[response(status = 503, content_type = "json")]
+    MediaToolUnavailable(String),
+    #[response(status = 500, content_type = "json")]
+    MediaToolFailed(String),
+    #[response(status = 502, content_type = "json")]
+    InvalidMediaToolOutput(String),
+    #[response(status = 413, content_type = "json")]
+    UploadTooLarge(String),
+    #[response(status = 507, content_type = "json")]
+    InsufficientStorage(&'static str),
 }
 
 #[derive(Serialize)]
@@ -84,6 +147,13 @@ impl Content {
         }.to_json(); }
         Self::Okay(&CON)
     }
+    pub fn created(id: u32, aliases: Vec<String>) -> Self {
+        Self::Created(Field {
+            content: std::iter::once((id, aliases)).collect::<HashMap<_, _>>(),
+            error: Json::Null,
+            status: true,
+        }.to_json())
+    }
     pub fn stream_hashes<'a>(iter: impl Iterator<Item = (
         impl Serialize + Hash + Eq,
         &'a [String],
@@ -106,9 +176,88 @@ impl Content {
             status: true,
         }.to_json())
     }
-    pub fn json_probe(json: Json) -> Self {
-        Self::JsonProbe(Field {
-            content: json,
+    pub fn chapters(chapters: &[core::Chapter]) -> Self {
+        Self::Chapters(Field {
+            content: chapters,
+            error: Json::Null,
+            status: true,
+        }.to_json())
+    }
+    // Each key maps to the body that its own request would have returned,
+    // including error bodies, so one failed key does not fail the batch.
+    pub fn batch<T: Serialize>(iter: impl Iterator<Item = (
+        String,
+        Result<T, Error>,
+    )>) -> Self
+    {
+        Self::Batch(Field {
+            content: iter
+                .map(|(key, result)| (key, match result {
+                    Ok(content) => serde_json::to_value(Field {
+                        content: content,
+                        error: Json::Null,
+                        status: true,
+                    }).unwrap_or(Json::Null),
+                    Err(e) => serde_json::from_str(e.body())
+                        .unwrap_or(Json::Null),
+                }))
+                .collect::<HashMap<_, _>>(),
+            error: Json::Null,
+            status: true,
+        }.to_json())
+    }
+    // next is the cursor for the following page, or null if this is the last.
+    pub fn alias_page(page: Vec<(u32, Vec<String>)>, limit: u32) -> Self {
+        #[derive(Serialize)]
+        struct Item {
+            id: u32,
+            aliases: Vec<String>,
+        }
+        #[derive(Serialize)]
+        struct Page {
+            items: Vec<Item>,
+            next: Option<u32>,
+        }
+        let next = match page.len() >= limit as usize {
+            true => page.last().map(|(id, _)| *id),
+            false => None,
+        };
+        Self::AliasPage(Field {
+            content: Page {
+                items: page.into_iter()
+                    .map(|(id, aliases)| Item { id: id, aliases: aliases })
+                    .collect(),
+                next: next,
+            },
+            error: Json::Null,
+            status: true,
+        }.to_json())
+    }
+    // An array rather than a map, so that the order of the search is kept.
+    pub fn alias_search(iter: impl Iterator<Item = (String, u32)>) -> Self {
+        #[derive(Serialize)]
+        struct Match {
+            alias: String,
+            id: u32,
+        }
+        Self::AliasSearch(Field {
+            content: iter
+                .map(|(alias, id)| Match { alias: alias, id: id })
+                .collect::<Vec<_>>(),
+            error: Json::Null,
+            status: true,
+        }.to_json())
+    }
+    pub fn probe(probe: &core::Probe) -> Self {
+        Self::Probe(Field {
+            content: probe,
+            error: Json::Null,
+            status: true,
+        }.to_json())
+    }
+    pub fn fsck(report: &core::FsckReport) -> Self {
+        Self::Fsck(Field {
+            content: report,
             error: Json::Null,
             status: true,
         }.to_json())
@@ -119,6 +268,25 @@ impl<'r> FileContent<'r> {
     pub fn new(value: core::ROFile<'r>) -> Result<Self, core::Error> {
         Self::try_from(value)
     }
+    // The stream's hash identifies its content, but not the bytes of the
+    // container it is extracted into, which differ between extractions. So
+    // the ETag is weak, and ranges are not served.
+    pub fn stream(
+        hash: &str,
+        value: core::ExtractedStream,
+    ) -> Result<Self, core::Error>
+    {
+        let len = value.file.metadata()
+            .map_err(|e| core::Error::FileSystemError(e))?
+            .len();
+        Ok(FileContent {
+            len: Some(len).filter(|l| *l != 0),
+            ext: Some(value.extension.to_string()),
+            etag: format!("W/\"{}\"", hash),
+            ranges: false,
+            reader: Box::new(value.file),
+        })
+    }
 }
 
 impl<'r> TryFrom<core::ROFile<'r>> for FileContent<'r> {
@@ -126,34 +294,165 @@ impl<'r> TryFrom<core::ROFile<'r>> for FileContent<'r> {
     fn try_from(value: core::ROFile<'r>) -> Result<Self, Self::Error> {
         let len = value.len()?;
         let ext = value.extension();
+        let etag = format!(
+            "\"{:x}-{:x}\"",
+            value.modified_nanos()?,
+            len.unwrap_or(0),
+        );
         match value.into_read() {
             Err(e) => Err(e),
             Ok(r) => Ok(FileContent {
                 len: len,
                 ext: ext,
+                etag: etag,
+                ranges: true,
                 reader: Box::new(r),
             }),
         }
     }
 }
 
+impl Range {
+    // Parses a single "bytes=" range. Returns None if the header should be
+    // ignored, including when it asks for multiple ranges.
+    fn parse(header: &str, len: u64) -> Option<Self> {
+        let spec = header.trim().strip_prefix("bytes=")?;
+        if spec.contains(',') {
+            return None;
+        }
+        let mut bounds = spec.splitn(2, '-').map(|s| s.trim());
+        let (start, end) = (bounds.next()?, bounds.next()?);
+        let range = match (start.is_empty(), end.is_empty()) {
+            (true, true) => return None,
+            (true, false) => match end.parse::<u64>().ok()? {
+                0 => Self::Unsatisfiable,
+                n => Self::Satisfiable(len.saturating_sub(n), len - 1),
+            },
+            (false, _) => {
+                let start = start.parse::<u64>().ok()?;
+                let end = match end.is_empty() {
+                    true => len - 1,
+                    false => match end.parse::<u64>().ok()? {
+                        // A last-byte-pos before the first-byte-pos makes
+                        // the header invalid, so it is ignored.
+                        end if end < start => return None,
+                        end => end.min(len - 1),
+                    },
+                };
+                match start < len {
+                    true => Self::Satisfiable(start, end),
+                    false => Self::Unsatisfiable,
+                }
+            },
+        };
+        Some(range)
+    }
+}
+
 impl<'r> Responder<'r> for FileContent<'r> {
-    fn respond_to(self, _: &rocket::Request) -> rocket::response::Result<'r> {
-        let file = BufReader::new(self.reader);
+    fn respond_to(
+        mut self,
+        request: &rocket::Request,
+    ) -> rocket::response::Result<'r>
+    {
+        let headers = request.headers();
         let mut response = Response::build();
-        match self.len {
-            Some(i) => response.raw_body(Body::Sized(file, i)),
-            None => response.streamed_body(file),
+        let accept_ranges = match self.ranges {
+            true => "bytes",
+            false => "none",
         };
-        if let Some(ext) = self.ext {
-            if let Some(ct) = ContentType::from_extension(&ext) {
-                response.header(ct);
-            }
+        response
+            .raw_header("Accept-Ranges", accept_ranges)
+            .raw_header("ETag", self.etag.clone());
+        if let Some(ct) = self.ext.as_ref().and_then(|e| content_type(e)) {
+            response.header(ct);
         }
+
+        // If-None-Match compares weakly, ignoring any W/ on either side.
+        let opaque = self.etag.trim_start_matches("W/");
+        let matches_etag = |header: &str| header
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == opaque);
+        if headers.get_one("If-None-Match").map_or(false, matches_etag) {
+            return response.status(Status::NotModified).ok();
+        }
+
+        // An If-Range that does not match means the client's partial copy is
+        // stale, so the whole file is sent instead.
+        let fresh = headers.get_one("If-Range")
+            .map_or(true, |tag| tag.trim() == self.etag);
+        let range = match (self.len, headers.get_one("Range")) {
+            (Some(len), Some(range)) if self.ranges && fresh => {
+                Range::parse(range, len)
+            },
+            _ => None,
+        };
+        match (range, self.len) {
+            (Some(Range::Satisfiable(start, end)), Some(len)) => {
+                self.reader.seek(SeekFrom::Start(start))
+                    .map_err(|_| Status::InternalServerError)?;
+                let length = end - start + 1;
+                response
+                    .status(Status::PartialContent)
+                    .raw_header(
+                        "Content-Range",
+                        format!("bytes {}-{}/{}", start, end, len),
+                    )
+                    .raw_body(Body::Sized(
+                        BufReader::new(self.reader).take(length),
+                        length,
+                    ));
+            },
+            (Some(Range::Unsatisfiable), Some(len)) => {
+                response
+                    .status(Status::RangeNotSatisfiable)
+                    .raw_header("Content-Range", format!("bytes */{}", len));
+            },
+            (_, Some(len)) => {
+                response.raw_body(Body::Sized(BufReader::new(self.reader), len));
+            },
+            (_, None) => {
+                response.streamed_body(BufReader::new(self.reader));
+            },
+        };
         response.ok()
     }
 }
 
+// Rocket does not know the Matroska extensions.
+fn content_type(extension: &str) -> Option<ContentType> {
+    match extension.to_lowercase().as_str() {
+        "mkv" => Some(ContentType::new("video", "x-matroska")),
+        "mka" => Some(ContentType::new("audio", "x-matroska")),
+        "mks" => Some(ContentType::new("application", "x-matroska")),
+        ext => ContentType::from_extension(ext),
+    }
+}
+
+impl Error {
+    fn body(&self) -> &str {
+        match self {
+            Self::InternalError(s) => s,
+            Self::FileNotFound(s) => s,
+            Self::AliasNotFound(s) => s,
+            Self::IdNotFound(s) => s,
+            Self::StreamHashesNotFound(s) => s,
+            Self::StreamNotFound(s) => s,
+            Self::StreamNotExtractable(s) => s,
+            Self::InvalidChapters(s) => s,
+            Self::InvalidMediaFile(s) => s,
+            Self::AliasesAlreadyExist(s) => s,
+            Self::AliasDoesNotMatchId(s) => s,
+            Self::MediaToolUnavailable(s) => s,
+            Self::MediaToolFailed(s) => s,
+            Self::InvalidMediaToolOutput(s) => s,
+            Self::UploadTooLarge(s) => s,
+            Self::InsufficientStorage(s) => s,
+        }
+    }
+}
+
 impl From<core::Error> for Error {
     fn from(value: core::Error) -> Self {
         
@@ -172,6 +471,18 @@ impl From<core::Error> for Error {
             alias: String,
         }
         #[derive(Serialize)]
+        struct StreamNotFound {
+            hash: String,
+        }
+        #[derive(Serialize)]
+        struct StreamNotExtractable {
+            hash: String,
+        }
+        #[derive(Serialize)]
+        struct UploadTooLarge {
+            limit: u64,
+        }
+        #[derive(Serialize)]
         struct BothNotFound {
             id: u32,
             alias: String,
@@ -185,7 +496,16 @@ impl From<core::Error> for Error {
                     text: "All provided stream hash values could not be located.",
                     kind: "StreamHashesNotFound",
                 },
-                status: true
+                status: false
+            }.to_json();
+            static ref INVALID_CHAPTERS: String = Field {
+                content: Json::Null,
+                error: ErrorInner {
+                    content: Json::Null,
+                    text: "Every chapter must have a start no later than its end.",
+                    kind: "InvalidChapters",
+                },
+                status: false
             }.to_json();
             static ref FILE_NOT_FOUND: String = Field {
                 content: Json::Null,
@@ -194,7 +514,7 @@ impl From<core::Error> for Error {
                     text: "The requested file could not be located on the server.",
                     kind: "FileNotFound",
                 },
-                status: true
+                status: false
             }.to_json();
             static ref INVALID_MEDIA_FILE: String = Field {
                 content: Json::Null,
@@ -203,7 +523,7 @@ impl From<core::Error> for Error {
                     text: "The provided file was not recognized as media by the server.",
                     kind: "InvalidMediaFile",
                 },
-                status: true
+                status: false
             }.to_json();
             static ref ALIASES_ALREADY_EXIST: String = Field {
                 content: Json::Null,
@@ -212,7 +532,16 @@ impl From<core::Error> for Error {
                     text: "At least one of the provided aliases already exist on the server.",
                     kind: "AliasesAlreadyExist",
                 },
-                status: true
+                status: false
+            }.to_json();
+            static ref INSUFFICIENT_STORAGE: String = Field {
+                content: Json::Null,
+                error: ErrorInner {
+                    content: Json::Null,
+                    text: "The server does not have enough free space for the provided file.",
+                    kind: "InsufficientStorage",
+                },
+                status: false
             }.to_json();
         }
         
@@ -220,6 +549,27 @@ impl From<core::Error> for Error {
             core::Error::StreamHashesNotFound => Self::StreamHashesNotFound(
                 &STREAM_HASHES_NOT_FOUND
             ),
+            core::Error::StreamNotFound(hash) => Self::StreamNotFound(Field {
+                content: Json::Null,
+                error: ErrorInner {
+                    content: StreamNotFound { hash: hash },
+                    text: "The provided stream hash did not exist in the file.",
+                    kind: "StreamNotFound",
+                },
+                status: false,
+            }.to_json()),
+            core::Error::StreamNotExtractable(hash) => Self::StreamNotExtractable(Field {
+                content: Json::Null,
+                error: ErrorInner {
+                    content: StreamNotExtractable { hash: hash },
+                    text: "The requested stream cannot be stored in a standalone file.",
+                    kind: "StreamNotExtractable",
+                },
+                status: false,
+            }.to_json()),
+            core::Error::InvalidChapters => Self::InvalidChapters(
+                &INVALID_CHAPTERS
+            ),
             core::Error::FileNotFound => Self::FileNotFound(&FILE_NOT_FOUND),
             core::Error::IdNotFound(id) => Self::IdNotFound(Field {
                 content: Json::Null,
@@ -228,7 +578,7 @@ impl From<core::Error> for Error {
                     text: "The provided ID did not exist on the server.",
                     kind: "IDNotFound",
                 },
-                status: true,
+                status: false,
             }.to_json()),
             core::Error::AliasNotFound(alias) => Self::AliasNotFound(Field {
                 content: Json::Null,
@@ -237,7 +587,7 @@ impl From<core::Error> for Error {
                     text: "The provided Alias did not exist on the server.",
                     kind: "AliasNotFound",
                 },
-                status: true,
+                status: false,
             }.to_json()),
             core::Error::AliasesAlreadyExist => Self::AliasesAlreadyExist(
                 &ALIASES_ALREADY_EXIST
@@ -249,11 +599,51 @@ impl From<core::Error> for Error {
                     text: "The provided Alias matches a different id.",
                     kind: "AliasDoesNotMatchID",
                 },
-                status: true,
+                status: false,
             }.to_json()),
             core::Error::InvalidMediaFile => Self::InvalidMediaFile(
                 &INVALID_MEDIA_FILE
             ),
+            core::Error::UploadTooLarge(limit) => Self::UploadTooLarge(Field {
+                content: Json::Null,
+                error: ErrorInner {
+                    content: UploadTooLarge { limit: limit },
+                    text: "The provided file is larger than the server accepts.",
+                    kind: "UploadTooLarge",
+                },
+                status: false,
+            }.to_json()),
+            core::Error::InsufficientStorage => Self::InsufficientStorage(
+                &INSUFFICIENT_STORAGE
+            ),
+            e @ core::Error::ProcessSpawnError(_) => Self::MediaToolUnavailable(Field {
+                content: Json::Null,
+                error: ErrorInner {
+                    content: InternalError::from(&e),
+                    text: "The server could not run its media tools.",
+                    kind: "MediaToolUnavailable",
+                },
+                status: false,
+            }.to_json()),
+            e @ core::Error::ProcessExitError(_) => Self::MediaToolFailed(Field {
+                content: Json::Null,
+                error: ErrorInner {
+                    content: InternalError::from(&e),
+                    text: "A media tool failed while processing the file.",
+                    kind: "MediaToolFailed",
+                },
+                status: false,
+            }.to_json()),
+            e @ core::Error::InvalidProcessOutput(_)
+            | e @ core::Error::InvalidHashOutput(_) => Self::InvalidMediaToolOutput(Field {
+                content: Json::Null,
+                error: ErrorInner {
+                    content: InternalError::from(&e),
+                    text: "A media tool returned output the server could not read.",
+                    kind: "InvalidMediaToolOutput",
+                },
+                status: false,
+            }.to_json()),
             any => Self::InternalError(Field {
                 content: Json::Null,
                 error: ErrorInner {
@@ -261,7 +651,7 @@ impl From<core::Error> for Error {
                     text: "Something went wrong!",
                     kind: "Internal",
                 },
-                status: true,
+                status: false,
             }.to_json()),
         }
     }
@@ -275,4 +665,47 @@ impl<T: StdError + ?Sized> From<&T> for InternalError {
             source: value.source().map(|e| Box::new(Self::from(e))),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Range::{self, Satisfiable, Unsatisfiable};
+
+    // Every test file is 1000 bytes long.
+    fn parse(header: &str) -> Option<Range> {
+        Range::parse(header, 1000)
+    }
+
+    #[test]
+    fn range_bounds() {
+        assert_eq!(parse("bytes=0-99"), Some(Satisfiable(0, 99)));
+        assert_eq!(parse(" bytes=500-"), Some(Satisfiable(500, 999)));
+        assert_eq!(parse("bytes=900-2000"), Some(Satisfiable(900, 999)));
+        assert_eq!(parse("bytes=999-999"), Some(Satisfiable(999, 999)));
+    }
+
+    #[test]
+    fn range_suffix() {
+        assert_eq!(parse("bytes=-100"), Some(Satisfiable(900, 999)));
+        assert_eq!(parse("bytes=-2000"), Some(Satisfiable(0, 999)));
+        assert_eq!(parse("bytes=-0"), Some(Unsatisfiable));
+    }
+
+    #[test]
+    fn range_start_past_end() {
+        assert_eq!(parse("bytes=1000-"), Some(Unsatisfiable));
+        assert_eq!(parse("bytes=1000-1100"), Some(Unsatisfiable));
+    }
+
+    // Such headers are ignored, and the whole file is sent.
+    #[test]
+    fn range_ignored() {
+        assert_eq!(parse("bytes=0-1,5-6"), None);
+        assert_eq!(parse("bytes=-"), None);
+        assert_eq!(parse("bytes=a-1"), None);
+        assert_eq!(parse("bytes=0"), None);
+        assert_eq!(parse("bytes=5-4"), None);
+        assert_eq!(parse("bytes=1100-1000"), None);
+        assert_eq!(parse("items=0-1"), None);
+    }
+}